@@ -0,0 +1,493 @@
+//! Public builder API for running an amalgamation
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    annotations::AnnotationLevel,
+    comments::CommentStripping,
+    compile_commands::{CompilationDatabase, CompileFlags},
+    compiler,
+    conditional::{MacroOption, Macros},
+    env_paths::{self, Language},
+    filter::{InliningFilter, InvertibleGlob, MultiIncludeFilter},
+    graph::{self, GraphFormat, Include},
+    line_paths::PathPrefixRemap,
+    logging::ErrorHandling,
+    process::{ErrorHandlingOpts, LineDirectiveStyle, OutputOpts, Processor},
+    resolve::IncludeResolver,
+    source_map::SourceMap,
+};
+
+/// Builder for combining C++ source files and the headers they include into a single output.
+///
+/// All options default to the same values as the corresponding cli flags.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// use cpp_amalgamate::{Amalgamator, ErrorHandling};
+///
+/// let mut output = Vec::new();
+/// let amalgamation = Amalgamator::new()
+///     .input("src/main.cpp")
+///     .search_dir("include")
+///     .filter("**/vendor/**".parse()?)
+///     .unresolvable_include(ErrorHandling::Warn)
+///     .amalgamate(&mut output)?;
+/// println!("Inlined {} headers", amalgamation.inlined_files.len());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Amalgamator {
+    inputs: Vec<PathBuf>,
+    quote_search_dirs: Vec<PathBuf>,
+    system_search_dirs: Vec<PathBuf>,
+    quote_filter_globs: Vec<InvertibleGlob>,
+    system_filter_globs: Vec<InvertibleGlob>,
+    multi_include_globs: Vec<InvertibleGlob>,
+    multi_include_unguarded: bool,
+    macro_options: Vec<MacroOption>,
+    compile_commands: Option<PathBuf>,
+    use_env_paths: bool,
+    system_dirs_from: Option<PathBuf>,
+    language: Language,
+    error_handling_opts: ErrorHandlingOpts,
+    output_opts: OutputOpts,
+}
+
+impl Default for Amalgamator {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            quote_search_dirs: Vec::new(),
+            system_search_dirs: Vec::new(),
+            quote_filter_globs: Vec::new(),
+            system_filter_globs: Vec::new(),
+            multi_include_globs: Vec::new(),
+            multi_include_unguarded: false,
+            macro_options: Vec::new(),
+            compile_commands: None,
+            use_env_paths: false,
+            system_dirs_from: None,
+            language: Language::default(),
+            error_handling_opts: ErrorHandlingOpts {
+                cyclic_include: ErrorHandling::Error,
+                unresolvable_quote_include: ErrorHandling::Ignore,
+                unresolvable_system_include: ErrorHandling::Ignore,
+            },
+            output_opts: OutputOpts::default(),
+        }
+    }
+}
+
+impl Amalgamator {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a source file to process.
+    ///
+    /// Source files are processed in the order they are added.
+    pub fn input(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.inputs.push(path.into());
+        self
+    }
+
+    /// Add multiple source files to process.
+    pub fn inputs<P: Into<PathBuf>>(&mut self, paths: impl IntoIterator<Item = P>) -> &mut Self {
+        self.inputs.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add a search directory for both quote and system includes.
+    pub fn search_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.quote_search_dir(&dir).system_search_dir(dir)
+    }
+
+    /// Add a search directory for quote includes.
+    pub fn quote_search_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.quote_search_dirs.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Add a search directory for system includes.
+    pub fn system_search_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.system_search_dirs.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Add a glob filtering which includes are inlined.
+    ///
+    /// Works like the `--filter` flag: matching headers are not inlined, unless the glob is
+    /// inverted. Globs are evaluated in the order they are added, with the latest matching glob
+    /// taking precedence.
+    pub fn filter(&mut self, glob: InvertibleGlob) -> &mut Self {
+        self.quote_filter_globs.push(glob.clone());
+        self.system_filter_globs.push(glob);
+        self
+    }
+
+    /// Add a glob filtering which quote includes are inlined.
+    pub fn quote_filter(&mut self, glob: InvertibleGlob) -> &mut Self {
+        self.quote_filter_globs.push(glob);
+        self
+    }
+
+    /// Add a glob filtering which system includes are inlined.
+    pub fn system_filter(&mut self, glob: InvertibleGlob) -> &mut Self {
+        self.system_filter_globs.push(glob);
+        self
+    }
+
+    /// Add a glob for headers which are inlined every time they are included.
+    pub fn multi_include(&mut self, glob: InvertibleGlob) -> &mut Self {
+        self.multi_include_globs.push(glob);
+        self
+    }
+
+    /// Inline headers without `#pragma once` or a header guard every time they are included.
+    pub fn multi_include_unguarded(&mut self, enabled: bool) -> &mut Self {
+        self.multi_include_unguarded = enabled;
+        self
+    }
+
+    /// Define or undefine a macro for evaluating `#if` directives.
+    ///
+    /// Options are applied in the order they are added.
+    pub fn macro_option(&mut self, option: MacroOption) -> &mut Self {
+        self.macro_options.push(option);
+        self
+    }
+
+    /// Use the search directories and macros of the source files from a `compile_commands.json`.
+    ///
+    /// The `-iquote`, `-I`, `-isystem` and `-idirafter` directories of each source file's entry are
+    /// searched after all directories added to the builder, with the same precedence as for the
+    /// compiler. Its `-D` and `-U` flags are applied on top of the macro options added to the
+    /// builder, which take precedence for the same macro. Each entry only applies while processing
+    /// its own source file, so the flags of one source file do not affect how the includes and
+    /// conditionals of another one are handled. However, as all source files are combined into a
+    /// single output, macros defined by the `#define` directives of one source file remain defined
+    /// in the following ones, and macros set by the flags of the previous source file are
+    /// considered unknown.
+    pub fn compile_commands(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.compile_commands = Some(path.into());
+        self
+    }
+
+    /// Search the directories listed in the `CPATH` environment variable, followed by those in
+    /// `C_INCLUDE_PATH` or `CPLUS_INCLUDE_PATH` depending on the language.
+    ///
+    /// These are searched for both quote and system includes, after all directories added to the
+    /// builder. Just like for GCC, `CPATH` is searched after the `-I` directories from
+    /// [`compile_commands`](Self::compile_commands), and the language specific variable after its
+    /// `-isystem` directories, with its headers being system headers.
+    pub fn use_env_paths(&mut self, enabled: bool) -> &mut Self {
+        self.use_env_paths = enabled;
+        self
+    }
+
+    /// Add the built-in system search directories of a compiler such as `g++` or `clang++`.
+    ///
+    /// These are discovered by running the compiler with `-E -v`, and are searched for system
+    /// includes after all other search directories. Compilers given without a directory are
+    /// searched for in `PATH`. The discovered directories are cached in a file in the user's cache
+    /// directory, keyed by the compiler's path and modification time.
+    pub fn system_dirs_from(&mut self, compiler: impl Into<PathBuf>) -> &mut Self {
+        self.system_dirs_from = Some(compiler.into());
+        self
+    }
+
+    /// Language of the source files (defaults to C++).
+    pub fn language(&mut self, language: Language) -> &mut Self {
+        self.language = language;
+        self
+    }
+
+    /// How to handle a cyclic include (defaults to error).
+    pub fn cyclic_include(&mut self, handling: ErrorHandling) -> &mut Self {
+        self.error_handling_opts.cyclic_include = handling;
+        self
+    }
+
+    /// How to handle an unresolvable include (defaults to ignore).
+    pub fn unresolvable_include(&mut self, handling: ErrorHandling) -> &mut Self {
+        self.unresolvable_quote_include(handling)
+            .unresolvable_system_include(handling)
+    }
+
+    /// How to handle an unresolvable quote include (defaults to ignore).
+    pub fn unresolvable_quote_include(&mut self, handling: ErrorHandling) -> &mut Self {
+        self.error_handling_opts.unresolvable_quote_include = handling;
+        self
+    }
+
+    /// How to handle an unresolvable system include (defaults to ignore).
+    pub fn unresolvable_system_include(&mut self, handling: ErrorHandling) -> &mut Self {
+        self.error_handling_opts.unresolvable_system_include = handling;
+        self
+    }
+
+    /// Add `#line` directives mapping the output back to the original files.
+    pub fn line_directives(&mut self, enabled: bool) -> &mut Self {
+        self.output_opts.line_directives = enabled;
+        self
+    }
+
+    /// Syntax of the line directives added by [`line_directives`](Self::line_directives).
+    pub fn line_directive_style(&mut self, style: LineDirectiveStyle) -> &mut Self {
+        self.output_opts.line_directive_style = style;
+        self
+    }
+
+    /// Write the paths in line directives and annotations relative to a directory.
+    ///
+    /// Paths which are remapped by [`remap_path_prefix`](Self::remap_path_prefix) are left as
+    /// remapped.
+    pub fn line_directive_base(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.output_opts.line_directive_base = Some(dir.into());
+        self
+    }
+
+    /// Replace a prefix of the paths in line directives and annotations, like
+    /// `--remap-path-prefix` for rustc.
+    ///
+    /// The prefix is matched against the canonical paths of the processed files. If several
+    /// remappings match, the one added last is applied.
+    pub fn remap_path_prefix(&mut self, remap: PathPrefixRemap) -> &mut Self {
+        self.output_opts.path_prefix_remaps.push(remap);
+        self
+    }
+
+    /// Add comments marking where each file begins and ends, and why include statements were
+    /// skipped or kept.
+    ///
+    /// With [`AnnotationLevel::Lines`], each copied line additionally ends with a comment stating
+    /// its file and line. Amalgamated files with annotations can be mapped back to their original
+    /// files using [`SourceMap::from_annotations`].
+    pub fn annotate(&mut self, level: AnnotationLevel) -> &mut Self {
+        self.output_opts.annotations = level;
+        self
+    }
+
+    /// Remove comments from the copied lines.
+    ///
+    /// Lines which only contained comments are left out of the output entirely.
+    pub fn strip_comments(&mut self, mode: CommentStripping) -> &mut Self {
+        self.output_opts.strip_comments = mode;
+        self
+    }
+
+    /// Remove header guards from inlined files.
+    pub fn strip_header_guards(&mut self, enabled: bool) -> &mut Self {
+        self.output_opts.strip_header_guards = enabled;
+        self
+    }
+
+    /// Returns the source files added so far.
+    pub fn input_files(&self) -> impl Iterator<Item = &Path> {
+        self.inputs.iter().map(PathBuf::as_path)
+    }
+
+    /// Returns the `compile_commands.json` to use, if any.
+    #[must_use]
+    pub fn compile_commands_file(&self) -> Option<&Path> {
+        self.compile_commands.as_deref()
+    }
+
+    /// Process all source files, writing the combined output to `writer`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a search directory or glob is invalid, if a file cannot be read or the output
+    /// cannot be written, or if an include error occurs which is configured to be an error.
+    pub fn amalgamate(&self, writer: impl Write) -> Result<Amalgamation> {
+        let database = self
+            .compile_commands
+            .as_deref()
+            .map(CompilationDatabase::load)
+            .transpose()?;
+        let (cpath_dirs, language_dirs) = if self.use_env_paths {
+            (
+                env_paths::search_dirs("CPATH"),
+                env_paths::search_dirs(self.language.include_path_var()),
+            )
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        let compiler_dirs = match &self.system_dirs_from {
+            Some(compiler) => compiler::system_dirs(compiler, self.language)?,
+            None => Vec::new(),
+        };
+        let mut output_opts = self.output_opts.clone();
+        if let Some(base) = &mut output_opts.line_directive_base {
+            *base = base.canonicalize().with_context(|| {
+                format!(
+                    "Failed to canonicalize line directive base \"{}\"",
+                    base.display()
+                )
+            })?;
+        }
+
+        let filter = InliningFilter::new(
+            self.quote_filter_globs.iter().cloned(),
+            self.system_filter_globs.iter().cloned(),
+        )?;
+        let multi_include_filter = MultiIncludeFilter::new(
+            self.multi_include_globs.iter().cloned(),
+            self.multi_include_unguarded,
+        )?;
+        let mut processor = Processor::new(
+            writer,
+            filter,
+            multi_include_filter,
+            Macros::new(self.macro_options.iter().cloned()),
+            self.error_handling_opts.clone(),
+            &output_opts,
+        );
+        for source_file in &self.inputs {
+            let flags = match &database {
+                Some(database) => database.flags_for(source_file)?.unwrap_or_default(),
+                None => CompileFlags::default(),
+            };
+
+            let mut quote_search_dirs = self.quote_search_dirs.clone();
+            let mut system_search_dirs = self.system_search_dirs.clone();
+            // Like those given by -isystem, directories only used for system includes contain
+            // system headers
+            let mut system_header_dirs: Vec<_> = system_search_dirs
+                .iter()
+                .filter(|dir| !quote_search_dirs.contains(dir))
+                .cloned()
+                .collect();
+            // Just like for GCC, CPATH is searched like -I, and the language specific variable
+            // like -isystem, both after the corresponding flags
+            let dirs = flags.dirs.into_iter().chain(cpath_dirs.iter().cloned());
+            let system_dirs: Vec<_> = flags
+                .system_dirs
+                .into_iter()
+                .chain(language_dirs.iter().cloned())
+                .chain(flags.after_dirs)
+                .collect();
+            let shared_dirs: Vec<_> = dirs.chain(system_dirs.iter().cloned()).collect();
+            extend_unique(&mut quote_search_dirs, flags.quote_dirs);
+            // Quote includes fall back to the system search directories
+            extend_unique(&mut quote_search_dirs, shared_dirs.iter().cloned());
+            extend_unique(&mut system_search_dirs, shared_dirs);
+            extend_unique(&mut system_header_dirs, system_dirs);
+            extend_unique(&mut system_search_dirs, compiler_dirs.iter().cloned());
+            extend_unique(&mut system_header_dirs, compiler_dirs.iter().cloned());
+            let resolver =
+                IncludeResolver::new(quote_search_dirs, system_search_dirs, system_header_dirs)?;
+
+            // Macro options added to the builder take precedence over those of the source file
+            let macro_options = flags
+                .macro_options
+                .into_iter()
+                .filter(|option| {
+                    !self
+                        .macro_options
+                        .iter()
+                        .any(|cli_option| cli_option.name() == option.name())
+                })
+                .collect();
+            processor.process(source_file, resolver, macro_options)?;
+        }
+        processor.finish()
+    }
+}
+
+/// Appends all items not yet contained in the list.
+fn extend_unique<T: PartialEq>(list: &mut Vec<T>, items: impl IntoIterator<Item = T>) {
+    for item in items {
+        if !list.contains(&item) {
+            list.push(item);
+        }
+    }
+}
+
+/// Summary of a finished amalgamation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Amalgamation {
+    /// Canonical paths of all processed source files, in processing order
+    pub source_files: Vec<PathBuf>,
+    /// Canonical paths of all inlined headers, in the order they were first inlined
+    pub inlined_files: Vec<PathBuf>,
+    /// Canonical paths of all directories searched for includes, including those from
+    /// `compile_commands.json`, environment variables and the compiler
+    pub search_dirs: Vec<PathBuf>,
+    /// All include statements encountered, in the order they were encountered
+    pub includes: Vec<Include>,
+    /// Where each line of the output was copied from
+    pub source_map: SourceMap,
+}
+
+/// Escapes a path or target name for use in a Makefile rule.
+fn escape_make(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ' ' | '#' => escaped.push('\\'),
+            '$' => escaped.push('$'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl Amalgamation {
+    /// Returns all files read during the amalgamation, starting with the source files.
+    pub fn dependencies(&self) -> impl Iterator<Item = &Path> {
+        self.source_files
+            .iter()
+            .chain(&self.inlined_files)
+            .map(PathBuf::as_path)
+    }
+
+    /// Writes a Makefile rule listing all files the target depends on.
+    ///
+    /// This matches the dependency files written by `gcc -MD`. With `phony_targets`, an empty rule
+    /// is added for every inlined header, just like with `-MP`. This prevents errors from make if
+    /// a header is removed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to `writer` fails.
+    pub fn write_dep_file(
+        &self,
+        mut writer: impl Write,
+        target: &str,
+        phony_targets: bool,
+    ) -> Result<()> {
+        let context = "Failed to write dependency file";
+        write!(writer, "{}:", escape_make(target)).context(context)?;
+        for path in self.dependencies() {
+            let escaped = escape_make(&path.to_string_lossy());
+            write!(writer, " \\\n  {}", escaped).context(context)?;
+        }
+        writeln!(writer).context(context)?;
+
+        if phony_targets {
+            for path in &self.inlined_files {
+                writeln!(writer, "\n{}:", escape_make(&path.to_string_lossy())).context(context)?;
+            }
+        }
+        writer.flush().context(context)
+    }
+
+    /// Writes the include graph, with an edge for every include statement.
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to `writer` fails.
+    pub fn write_graph(&self, writer: impl Write, format: GraphFormat) -> Result<()> {
+        graph::write(writer, &self.includes, format)
+    }
+}
@@ -0,0 +1,159 @@
+//! Comments describing where the parts of the output were copied from
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Error, Result};
+use regex::Regex;
+
+use crate::source_map::{SourceMap, SourceMapBuilder};
+
+/// Comments added to the output, which describe where its parts were copied from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnnotationLevel {
+    /// No comments are added
+    None,
+    /// Comments mark the beginning and end of each file, as well as each include statement which
+    /// was skipped or kept
+    Includes,
+    /// In addition to the comments for includes, each copied line ends with a comment stating its
+    /// file and line
+    Lines,
+}
+
+impl AnnotationLevel {
+    pub const NAMES: [&'static str; 3] = ["none", "includes", "lines"];
+}
+
+impl Default for AnnotationLevel {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl FromStr for AnnotationLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Self::None,
+            "includes" => Self::Includes,
+            "lines" => Self::Lines,
+            _ => bail!("Invalid annotation level: \"{}\"", s),
+        })
+    }
+}
+
+/// Returns the comment marking the beginning of a file, which was included using the given name.
+pub(crate) fn begin(name: &str, file_name: &Path) -> String {
+    format!("begin {} ({})", name, file_name.display())
+}
+
+pub(crate) fn end(name: &str) -> String {
+    format!("end {}", name)
+}
+
+pub(crate) fn skipped(directive: &str) -> String {
+    format!("{} skipped: already inlined", directive)
+}
+
+pub(crate) fn kept(directive: &str, reason: &str) -> String {
+    format!("{} kept: {}", directive, reason)
+}
+
+/// Returns the comment for a line which was removed without replacement, such as `#pragma once`.
+pub(crate) fn removed(directive: &str) -> String {
+    format!("{} removed", directive)
+}
+
+/// Appends a comment with the file and line it was copied from to a line.
+pub(crate) fn with_line_origin(line: &str, file_name: &Path, num: usize) -> String {
+    let text = line.trim_end_matches(|c| c == '\n' || c == '\r');
+    format!(
+        "{} // {}:{}{}",
+        text,
+        file_name.display(),
+        num,
+        &line[text.len()..]
+    )
+}
+
+/// Splits the comment added by [`with_line_origin`] off a line, if it states the given file.
+///
+/// Returns the line without the comment and the line number stated by it.
+pub(crate) fn split_line_origin(line: &str, file_name: &Path) -> Option<(String, usize)> {
+    let text = line.trim_end_matches(|c| c == '\n' || c == '\r');
+    let (code, origin) = text.rsplit_once(" // ")?;
+    let (name, num) = origin.rsplit_once(':')?;
+    if Path::new(name) != file_name {
+        return None;
+    }
+    let num = num.parse().ok()?;
+    Some((format!("{}{}", code, &line[text.len()..]), num))
+}
+
+/// A file whose beginning was seen, but not its end yet.
+#[derive(Debug)]
+struct OpenFile {
+    name: String,
+    path: PathBuf,
+    next_line: usize,
+}
+
+/// Recovers the source map from the annotations in an amalgamated file.
+///
+/// Lines are counted from the comment marking the beginning of their file, with each include
+/// statement that was replaced by a comment or an inlined file counting as one line. Line origin
+/// comments take precedence over the count.
+pub(crate) fn recover_source_map(content: &str) -> SourceMap {
+    let begin_regex = Regex::new(r#"^\s*// begin (<[^>]*>|"[^"]*"|.*?) \((.*)\)\s*$"#)
+        .expect("invalid hardcoded regex");
+    let replaced_regex = Regex::new(
+        r"^\s*// (?:#|%:|(?:export\s+)?import\b).* (?:skipped: already inlined|removed)\s*$",
+    )
+    .expect("invalid hardcoded regex");
+    let kept_regex = Regex::new(r"^\s*// (?:#|%:|(?:export\s+)?import\b).* kept: ")
+        .expect("invalid hardcoded regex");
+
+    let mut builder = SourceMapBuilder::new();
+    let mut open_files: Vec<OpenFile> = Vec::new();
+    for line in content.split_inclusive('\n') {
+        if let Some(captures) = begin_regex.captures(line) {
+            open_files.push(OpenFile {
+                name: captures[1].to_owned(),
+                path: PathBuf::from(&captures[2]),
+                next_line: 1,
+            });
+            builder.added(line);
+            continue;
+        }
+
+        let file = if let Some(file) = open_files.last_mut() {
+            file
+        } else {
+            builder.added(line);
+            continue;
+        };
+        if line.trim() == format!("// {}", end(&file.name)) {
+            open_files.pop();
+            if let Some(includer) = open_files.last_mut() {
+                // Skips the include statement the file replaced
+                includer.next_line += 1;
+            }
+            builder.added(line);
+        } else if replaced_regex.is_match(line) {
+            file.next_line += 1;
+            builder.added(line);
+        } else if kept_regex.is_match(line) {
+            builder.added(line);
+        } else {
+            if let Some((_, num)) = split_line_origin(line, &file.path) {
+                file.next_line = num;
+            }
+            builder.copied(&file.path, file.next_line, line);
+            file.next_line += 1;
+        }
+    }
+    builder.finish()
+}
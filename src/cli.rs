@@ -1,11 +1,14 @@
 //! Definition and parsing of cli arguments
 use std::path::{Path, PathBuf};
 
-use clap::{ArgMatches, FromArgMatches, IntoApp, Parser};
+use clap::{ArgMatches, FromArgMatches, IntoApp, Parser, Subcommand};
 use itertools::Itertools;
 use log::LevelFilter;
 
-use crate::{filter::InvertibleGlob, logging::ErrorHandling};
+use cpp_amalgamate::{
+    AnnotationLevel, CommentStripping, ErrorHandling, GraphFormat, InvertibleGlob, Language,
+    LineDirectiveStyle, MacroOption, PathPrefixRemap,
+};
 
 const ABOUT: &str = "cpp-amalgamate recursively combines C++ source files and the headers they
 include into a single output file. It tracks which headers have been included and skips any further
@@ -21,6 +24,7 @@ const HELP_TEMPLATE: &str = "\
 ";
 
 #[derive(Debug, Parser)]
+#[allow(clippy::struct_excessive_bools)]
 #[clap(
     author,
     version,
@@ -29,9 +33,10 @@ const HELP_TEMPLATE: &str = "\
     hide_possible_values=true,
     // To make this work, we cannot use default_value for arguments.
     arg_required_else_help=true,
+    subcommand_negates_reqs=true,
 )]
 pub struct Opts {
-    /// ArgMatches used to create this instance
+    /// `ArgMatches` used to create this instance
     #[clap(skip)]
     matches: ArgMatches,
 
@@ -39,17 +44,74 @@ pub struct Opts {
     #[clap(required = true, parse(from_os_str))]
     pub files: Vec<PathBuf>,
 
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Redirect output to a file
-    #[clap(short, long, parse(from_os_str), value_name = "file")]
+    #[clap(short, long, parse(from_os_str), value_name = "file", global = true)]
     pub output: Option<PathBuf>,
 
+    /// Write a Makefile rule listing all files read to a dependency file.
+    ///
+    /// This works like the -MD and -MF flags of gcc, allowing build systems such as make or ninja
+    /// to run cpp-amalgamate again whenever one of the inlined headers changes.
+    #[clap(long, parse(from_os_str), value_name = "file", global = true)]
+    pub dep_file: Option<PathBuf>,
+
+    /// Target of the rule in the dependency file (defaults to the output file)
+    #[clap(long, value_name = "name", requires = "dep-file", global = true)]
+    pub dep_target: Option<String>,
+
+    /// Add an empty rule for every inlined header to the dependency file.
+    ///
+    /// Works like the -MP flag of gcc, preventing make from failing if a header is deleted.
+    #[clap(
+        long,
+        requires = "dep-file",
+        overrides_with = "no-dep-phony",
+        global = true
+    )]
+    pub dep_phony: bool,
+
+    /// Don't add empty rules to the dependency file, even if enabled in the configuration file
+    #[clap(long, overrides_with = "dep-phony", global = true)]
+    pub no_dep_phony: bool,
+
+    /// Write the include graph to a file.
+    ///
+    /// The graph contains an edge for every include statement, including those which were not
+    /// inlined, labeled with the include as written, its line, and what was done with it.
+    #[clap(long, parse(from_os_str), value_name = "file", global = true)]
+    pub graph: Option<PathBuf>,
+
+    /// Format of the include graph.
+    ///
+    /// Either dot (for Graphviz) or json. Defaults to json for files ending in .json, and dot
+    /// otherwise.
+    #[clap(
+        long,
+        value_name = "format",
+        possible_values = &GraphFormat::NAMES,
+        requires = "graph",
+        global = true
+    )]
+    pub graph_format: Option<GraphFormat>,
+
+    /// Write a JSON source map to a file.
+    ///
+    /// The source map records the file and line every line of the output was copied from. Unlike
+    /// --line-directives, this does not change the output.
+    #[clap(long, parse(from_os_str), value_name = "file", global = true)]
+    pub source_map: Option<PathBuf>,
+
     /// Add a search directory for both system and quote includes
     #[clap(
         short,
         long,
         value_name = "dir",
         multiple_occurrences = true,
-        number_of_values = 1
+        number_of_values = 1,
+        global = true
     )]
     dir: Vec<PathBuf>,
 
@@ -59,7 +121,8 @@ pub struct Opts {
         parse(from_os_str),
         value_name = "dir",
         multiple_occurrences = true,
-        number_of_values = 1
+        number_of_values = 1,
+        global = true
     )]
     dir_quote: Vec<PathBuf>,
 
@@ -69,10 +132,47 @@ pub struct Opts {
         parse(from_os_str),
         value_name = "dir",
         multiple_occurrences = true,
-        number_of_values = 1
+        number_of_values = 1,
+        global = true
     )]
     dir_system: Vec<PathBuf>,
 
+    /// Use search directories and macros from a compilation database.
+    ///
+    /// The include directories (-iquote, -I, -isystem, and -idirafter) and macros (-D and -U) of
+    /// the entry for each given source file are used as if they were passed to cpp-amalgamate, but
+    /// only while processing that source file. Search directories given using -d, --dir-quote or
+    /// --dir-system and macros given using -D or -U take precedence over them.
+    #[clap(long, parse(from_os_str), value_name = "file", global = true)]
+    pub compile_commands: Option<PathBuf>,
+
+    /// Add search directories from the CPATH and C_INCLUDE_PATH/CPLUS_INCLUDE_PATH variables.
+    ///
+    /// Just like for gcc, CPATH is searched like -I and C_INCLUDE_PATH or CPLUS_INCLUDE_PATH,
+    /// depending on --language, like -isystem, each after the corresponding directories from
+    /// --compile-commands. An empty entry stands for the current directory. Search directories
+    /// given on the command line are searched first.
+    #[allow(clippy::doc_markdown)]
+    #[clap(long, overrides_with = "no-use-env-paths", global = true)]
+    pub use_env_paths: bool,
+
+    /// Don't search the directories from environment variables, even if enabled in the
+    /// configuration file
+    #[clap(long, overrides_with = "use-env-paths", global = true)]
+    pub no_use_env_paths: bool,
+
+    /// Add the built-in system search directories of a compiler.
+    ///
+    /// These are discovered by running the compiler (e.g., g++ or clang++) with -E -v, and are
+    /// searched for system includes after all other search directories. The result is cached
+    /// until the compiler is modified.
+    #[clap(long, parse(from_os_str), value_name = "compiler", global = true)]
+    pub system_dirs_from: Option<PathBuf>,
+
+    /// Language of the source files, either c or c++ [default: c++].
+    #[clap(long, value_name = "lang", possible_values = &Language::NAMES, global = true)]
+    pub language: Option<Language>,
+
     /// Filter which includes are inlined.
     ///
     /// By default, cpp-amalgamate inlines every header it can resolve using the given search
@@ -89,7 +189,8 @@ pub struct Opts {
         long,
         value_name = "glob",
         multiple_occurrences = true,
-        number_of_values = 1
+        number_of_values = 1,
+        global = true
     )]
     filter: Vec<InvertibleGlob>,
 
@@ -100,7 +201,8 @@ pub struct Opts {
         long,
         value_name = "glob",
         multiple_occurrences = true,
-        number_of_values = 1
+        number_of_values = 1,
+        global = true
     )]
     filter_quote: Vec<InvertibleGlob>,
 
@@ -111,10 +213,68 @@ pub struct Opts {
         long,
         value_name = "glob",
         multiple_occurrences = true,
-        number_of_values = 1
+        number_of_values = 1,
+        global = true
     )]
     filter_system: Vec<InvertibleGlob>,
 
+    /// Define a macro for evaluating #if directives.
+    ///
+    /// The value is either 'name', which defines the macro as 1, or 'name=value'. Includes are
+    /// only inlined if they are in a branch known to be taken, based on the macros defined by this
+    /// option, -U and the #define directives encountered so far. Macros undefined by -U or #undef
+    /// evaluate to 0, while any other identifier might be predefined by the compiler and is
+    /// unknown. Branches whose condition cannot be decided, for example because it uses such an
+    /// identifier or a function-like macro, are kept as is without inlining any of their includes.
+    #[clap(
+        short = 'D',
+        long,
+        value_name = "name[=value]",
+        parse(try_from_str = MacroOption::parse_define),
+        multiple_occurrences = true,
+        number_of_values = 1,
+        global = true
+    )]
+    define: Vec<MacroOption>,
+
+    /// Undefine a macro previously defined with -D
+    #[clap(
+        short = 'U',
+        long,
+        value_name = "name",
+        parse(try_from_str = MacroOption::parse_undefine),
+        multiple_occurrences = true,
+        number_of_values = 1,
+        global = true
+    )]
+    undefine: Vec<MacroOption>,
+
+    /// Inline matching headers every time they are included.
+    ///
+    /// By default, cpp-amalgamate inlines every header at most once. Headers matching this glob are
+    /// instead inlined at every include site, which is needed for X-macro tables or .inc fragments.
+    /// As with --filter, globs can be inverted with a leading '!' and the latest matching glob takes
+    /// precedence. Headers with a header guard are only inlined again if the guard macro is not
+    /// known to be defined.
+    #[clap(
+        long,
+        value_name = "glob",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        global = true
+    )]
+    multi_include: Vec<InvertibleGlob>,
+
+    /// Inline headers without #pragma once or a header guard every time they are included.
+    ///
+    /// Headers matched by --multi-include take precedence over this.
+    #[clap(long, overrides_with = "no-multi-include-unguarded", global = true)]
+    pub multi_include_unguarded: bool,
+
+    /// Inline unguarded headers at most once, even if enabled in the configuration file
+    #[clap(long, overrides_with = "multi-include-unguarded", global = true)]
+    pub no_multi_include_unguarded: bool,
+
     /// How to handle an unresolvable include.
     ///
     /// By default, cpp-amalgamate ignores includes which cannot be resolved to allow specifying
@@ -127,7 +287,8 @@ pub struct Opts {
         long,
         value_name = "handling",
         possible_values = &ErrorHandling::NAMES,
-        conflicts_with_all = &["unresolvable-quote-include", "unresolvable-system-include"]
+        conflicts_with_all = &["unresolvable-quote-include", "unresolvable-system-include"],
+        global = true
     )]
     unresolvable_include: Option<ErrorHandling>,
 
@@ -138,6 +299,7 @@ pub struct Opts {
         long,
         value_name = "handling",
         possible_values = &ErrorHandling::NAMES,
+        global = true
     )]
     unresolvable_quote_include: Option<ErrorHandling>,
 
@@ -148,6 +310,7 @@ pub struct Opts {
         long,
         value_name = "handling",
         possible_values = &ErrorHandling::NAMES,
+        global = true
     )]
     unresolvable_system_include: Option<ErrorHandling>,
 
@@ -159,26 +322,193 @@ pub struct Opts {
         long,
         value_name = "handling",
         possible_values = &ErrorHandling::NAMES,
+        global = true
     )]
     cyclic_include: Option<ErrorHandling>,
 
     /// Increase the verbosity of the output (can be passed multiple times).
     ///
     /// By default, only warnings and errors are reported. Passing '-v' includes info, '-vv' debug,
-    /// and '-vvv` trace log messages.
-    #[clap(short, long, parse(from_occurrences))]
+    /// and '-vvv' trace log messages.
+    #[clap(short, long, parse(from_occurrences), global = true)]
     verbose: i8,
 
     /// Report only errors (-q) or nothing (-qq)
-    #[clap(short, long, parse(from_occurrences), conflicts_with = "verbose")]
+    #[clap(
+        short,
+        long,
+        parse(from_occurrences),
+        conflicts_with = "verbose",
+        global = true
+    )]
     quiet: i8,
 
     /// Add #line directives.
     ///
     /// These allow compilers and debuggers to resolve lines in the amalgamated file to their
-    /// original files.
-    #[clap(long)]
-    pub line_directives: bool,
+    /// original files. With --line-directives=gcc, GCC-style linemarkers are added instead, which
+    /// additionally mark entering (flag 1) and returning from (flag 2) an inlined header, as well as
+    /// system headers (flag 3), just like the output of gcc -E.
+    #[clap(
+        long,
+        value_name = "style",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "line",
+        possible_values = &LineDirectiveStyle::NAMES,
+        overrides_with = "no-line-directives",
+        global = true
+    )]
+    pub line_directives: Option<LineDirectiveStyle>,
+
+    /// Don't add #line directives, even if enabled in the configuration file
+    #[clap(long, overrides_with = "line-directives", global = true)]
+    pub no_line_directives: bool,
+
+    /// Write the paths in line directives and annotations relative to a directory.
+    ///
+    /// Together with --remap-path-prefix, this makes the output independent of where the files are
+    /// located, e.g., to commit it to version control.
+    #[clap(long, parse(from_os_str), value_name = "dir", global = true)]
+    pub line_directive_base: Option<PathBuf>,
+
+    /// Replace a path prefix in line directives and annotations.
+    ///
+    /// FROM is matched against the absolute paths of the processed files, and replaced by TO if it
+    /// is a prefix. If several remappings match, the one given last is applied. Remapped paths are
+    /// not made relative to --line-directive-base.
+    #[clap(
+        long,
+        value_name = "FROM=TO",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        global = true
+    )]
+    pub remap_path_prefix: Vec<PathPrefixRemap>,
+
+    /// Add comments describing where the parts of the output come from.
+    ///
+    /// These mark the beginning and end of each file, as well as include statements which were
+    /// skipped or kept, along with the reason. With --annotate=lines, each copied line additionally
+    /// ends with a comment stating its file and line.
+    #[clap(
+        long,
+        value_name = "level",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "includes",
+        possible_values = &AnnotationLevel::NAMES,
+        global = true
+    )]
+    pub annotate: Option<AnnotationLevel>,
+
+    /// Run again whenever one of the processed files changes.
+    ///
+    /// After each run, cpp-amalgamate watches the source files, all inlined headers, all searched
+    /// directories, including those from --compile-commands, --use-env-paths and
+    /// --system-dirs-from, and the configuration file for changes. Errors are reported without
+    /// exiting.
+    #[clap(short, long, global = true)]
+    pub watch: bool,
+
+    /// Remove comments from the output.
+    ///
+    /// Comments are removed from all copied lines, taking string, character and raw string
+    /// literals into account. Lines which only contained comments are left out entirely. With
+    /// --strip-comments=keep-licenses, /*! blocks and comments mentioning a license or copyright
+    /// are kept.
+    #[clap(
+        long,
+        value_name = "mode",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "all",
+        possible_values = &CommentStripping::NAMES,
+        global = true
+    )]
+    pub strip_comments: Option<CommentStripping>,
+
+    /// Remove header guards from inlined files.
+    ///
+    /// A header guard is an #ifndef/#define pair at the start of a file with a matching #endif at
+    /// its end. Since cpp-amalgamate only inlines a guarded header again if its guard macro is not
+    /// known to be defined, these are not needed in the output. The guard macro is still
+    /// considered defined after the header, just like for the compiler.
+    #[clap(long, overrides_with = "no-strip-header-guards", global = true)]
+    pub strip_header_guards: bool,
+
+    /// Keep header guards, even if stripping them is enabled in the configuration file
+    #[clap(long, overrides_with = "strip-header-guards", global = true)]
+    pub no_strip_header_guards: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Build targets declared in cpp-amalgamate.toml.
+    ///
+    /// The configuration file is searched for in the current directory and its parents. Flags
+    /// given on the command line take precedence over the values in the configuration file, and
+    /// search directories given on the command line are searched first.
+    Build {
+        /// Targets to build (defaults to all targets)
+        #[clap(value_name = "target")]
+        targets: Vec<String>,
+    },
+
+    /// Map lines of an amalgamated file back to the files they were copied from.
+    ///
+    /// Prints the original location of the given line. Without a line, compiler diagnostics are
+    /// read from the standard input, and every location in the amalgamated file is rewritten to the
+    /// original location. The mapping is read from a source map written using --source-map, or
+    /// recovered from the line directives in the amalgamated file.
+    Locate {
+        /// The amalgamated file
+        #[clap(parse(from_os_str), value_name = "file")]
+        file: PathBuf,
+
+        /// Line of the amalgamated file to locate
+        #[clap(value_name = "line")]
+        line: Option<usize>,
+
+        /// Source map of the amalgamated file
+        #[clap(long, parse(from_os_str), value_name = "file")]
+        map: Option<PathBuf>,
+
+        /// Name the amalgamated file is referred to by in the diagnostics.
+        ///
+        /// By default, locations are recognized if they refer to the file as given or by its
+        /// absolute path. This adds further names, e.g., if it was compiled in another directory.
+        #[clap(
+            long,
+            value_name = "name",
+            multiple_occurrences = true,
+            number_of_values = 1
+        )]
+        name: Vec<String>,
+    },
+
+    /// Reconstruct the files an amalgamated file was created from.
+    ///
+    /// This ports changes made directly to the amalgamated file back to the original files. By
+    /// default, a diff from the current to the reconstructed files is shown. Include statements,
+    /// #pragma once, and other lines removed during the amalgamation are restored from the current
+    /// files. The files are located using a source map written using --source-map, or using the
+    /// line directives in the amalgamated file.
+    Split {
+        /// The amalgamated file
+        #[clap(parse(from_os_str), value_name = "file")]
+        file: PathBuf,
+
+        /// Source map of the amalgamated file
+        #[clap(long, parse(from_os_str), value_name = "file")]
+        map: Option<PathBuf>,
+
+        /// Write the reconstructed files to a directory instead of showing a diff.
+        ///
+        /// The files are placed relative to the deepest directory containing all of them.
+        #[clap(long, parse(from_os_str), value_name = "dir")]
+        out_dir: Option<PathBuf>,
+    },
 }
 
 fn with_indices<'a, T>(
@@ -241,20 +571,30 @@ impl Opts {
         self.merge_by_cli_order(&self.filter, "filter", &self.filter_system, "filter-system")
     }
 
-    pub fn unresolvable_quote_include_handling(&self) -> ErrorHandling {
+    pub fn multi_include_globs(&self) -> impl Iterator<Item = &InvertibleGlob> {
+        self.multi_include.iter()
+    }
+
+    /// Returns the -D and -U options in the order given on the cli.
+    pub fn macro_options(&self) -> impl Iterator<Item = &MacroOption> {
+        self.merge_by_cli_order(&self.define, "define", &self.undefine, "undefine")
+    }
+
+    /// Returns how to handle unresolvable quote includes, if given on the cli.
+    pub fn unresolvable_quote_include_handling(&self) -> Option<ErrorHandling> {
         self.unresolvable_include
             .or(self.unresolvable_quote_include)
-            .unwrap_or(ErrorHandling::Ignore)
     }
 
-    pub fn unresolvable_system_include_handling(&self) -> ErrorHandling {
+    /// Returns how to handle unresolvable system includes, if given on the cli.
+    pub fn unresolvable_system_include_handling(&self) -> Option<ErrorHandling> {
         self.unresolvable_include
             .or(self.unresolvable_system_include)
-            .unwrap_or(ErrorHandling::Ignore)
     }
 
-    pub fn cyclic_include_handling(&self) -> ErrorHandling {
-        self.cyclic_include.unwrap_or(ErrorHandling::Error)
+    /// Returns how to handle cyclic includes, if given on the cli.
+    pub fn cyclic_include_handling(&self) -> Option<ErrorHandling> {
+        self.cyclic_include
     }
 
     pub fn log_level(&self) -> LevelFilter {
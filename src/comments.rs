@@ -0,0 +1,118 @@
+//! Removal of comments from the copied lines
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+
+use crate::lexer;
+
+/// Which comments are removed from the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentStripping {
+    /// All comments are kept
+    None,
+    /// All comments are removed
+    All,
+    /// Comments are removed, except for `/*!` blocks and comments mentioning a license or
+    /// copyright
+    KeepLicenses,
+}
+
+impl CommentStripping {
+    pub const NAMES: [&'static str; 3] = ["none", "all", "keep-licenses"];
+
+    fn removes(self, comment: &str) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::KeepLicenses => {
+                let lowercase = comment.to_lowercase();
+                !(comment.starts_with("/*!")
+                    || lowercase.contains("license")
+                    || lowercase.contains("copyright"))
+            }
+        }
+    }
+}
+
+impl Default for CommentStripping {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl FromStr for CommentStripping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Self::None,
+            "all" => Self::All,
+            "keep-licenses" => Self::KeepLicenses,
+            _ => bail!("Invalid comment stripping mode: \"{}\"", s),
+        })
+    }
+}
+
+/// Returns whether a file contains any comment which would be removed in the given mode.
+pub fn has_removable_comments(content: &str, mode: CommentStripping) -> bool {
+    lexer::comment_ranges(content)
+        .into_iter()
+        .any(|range| mode.removes(&content[range]))
+}
+
+/// Returns whether a line was left out of the output because it only consisted of comments.
+pub fn is_comment_only(line: &str, stripped: &str) -> bool {
+    stripped.trim().is_empty() && !line.trim().is_empty()
+}
+
+/// Removes comments from a file, returning its lines including their line endings.
+///
+/// Every line of the file is returned, even if it only consisted of comments. A comment between
+/// code on the same line is replaced by a single space, so that the tokens around it stay separate,
+/// while whitespace before a comment at the end of a line is removed along with it.
+pub fn strip_comments(content: &str, mode: CommentStripping) -> Vec<String> {
+    let mut removed = lexer::comment_ranges(content)
+        .into_iter()
+        .filter(|range| mode.removes(&content[range.clone()]))
+        .peekable();
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    for line in content.split_inclusive('\n') {
+        let text_len = line.trim_end_matches(|c| c == '\n' || c == '\r').len();
+        let line_end = line_start + text_len;
+        let mut stripped = String::with_capacity(line.len());
+        let mut pos = line_start;
+        let mut ends_in_comment = false;
+        while let Some(range) = removed.peek() {
+            if range.start > line_end {
+                break;
+            }
+
+            let start = range.start.max(line_start);
+            let end = range.end.min(line_end);
+            stripped.push_str(&content[pos..start]);
+            pos = end;
+            ends_in_comment = end == line_end;
+            let separates_code = !ends_in_comment
+                && stripped.ends_with(|c: char| !c.is_whitespace())
+                && content[end..].starts_with(|c: char| !c.is_whitespace());
+            if separates_code {
+                stripped.push(' ');
+            }
+
+            if range.end > line_end {
+                break;
+            }
+            removed.next();
+        }
+
+        stripped.push_str(&content[pos..line_end]);
+        if ends_in_comment {
+            stripped.truncate(stripped.trim_end().len());
+        }
+        stripped.push_str(&line[text_len..]);
+        lines.push(stripped);
+        line_start += line.len();
+    }
+    lines
+}
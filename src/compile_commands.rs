@@ -0,0 +1,215 @@
+//! Extraction of search directories and macros from a `compile_commands.json`
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::{conditional::MacroOption, logging::debug_file_name};
+
+/// A single entry of a compilation database.
+///
+/// See <https://clang.llvm.org/docs/JSONCompilationDatabase.html> for the format.
+#[derive(Debug, Deserialize)]
+struct Entry {
+    directory: PathBuf,
+    file: PathBuf,
+    #[serde(default)]
+    arguments: Option<Vec<String>>,
+    #[serde(default)]
+    command: Option<String>,
+}
+
+/// Splits a command line into arguments, following the quoting rules of a POSIX shell.
+fn split_command(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(current.take()),
+            '\'' => {
+                let arg = current.get_or_insert_with(String::new);
+                arg.extend(chars.by_ref().take_while(|&quoted| quoted != '\''));
+            }
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                while let Some(quoted) = chars.next() {
+                    match quoted {
+                        '"' => break,
+                        '\\' => arg.extend(chars.next()),
+                        _ => arg.push(quoted),
+                    }
+                }
+            }
+            '\\' => current.get_or_insert_with(String::new).extend(chars.next()),
+            _ => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    args
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Flag {
+    QuoteDir,
+    Dir,
+    SystemDir,
+    AfterDir,
+    Define,
+    Undefine,
+}
+
+/// Flags which are relevant to cpp-amalgamate, each of which is followed by a value.
+const FLAGS: [(&str, Flag); 8] = [
+    ("-iquote", Flag::QuoteDir),
+    ("-I", Flag::Dir),
+    ("--include-directory=", Flag::Dir),
+    ("--include-directory", Flag::Dir),
+    ("-isystem", Flag::SystemDir),
+    ("-idirafter", Flag::AfterDir),
+    ("-D", Flag::Define),
+    ("-U", Flag::Undefine),
+];
+
+/// Search directories and macros given to the compiler for a single source file.
+#[derive(Debug, Default)]
+///
+/// The directories are kept separate by flag, since they are searched in a fixed order together
+/// with those from other sources, such as environment variables.
+pub struct CompileFlags {
+    /// The `-iquote` directories, only searched for quote includes
+    pub quote_dirs: Vec<PathBuf>,
+    /// The `-I` directories
+    pub dirs: Vec<PathBuf>,
+    /// The `-isystem` directories, whose headers are system headers
+    pub system_dirs: Vec<PathBuf>,
+    /// The `-idirafter` directories, which are searched after the built-in system directories and
+    /// contain system headers as well
+    pub after_dirs: Vec<PathBuf>,
+    pub macro_options: Vec<MacroOption>,
+}
+
+impl CompileFlags {
+    fn parse(arguments: &[String], directory: &Path) -> Result<Self> {
+        let mut quote_dirs = Vec::new();
+        let mut dirs = Vec::new();
+        let mut system_dirs = Vec::new();
+        let mut after_dirs = Vec::new();
+        let mut macro_options = Vec::new();
+
+        // The first argument is the compiler itself
+        let mut args = arguments.iter().skip(1);
+        while let Some(arg) = args.next() {
+            let matched = FLAGS.iter().find_map(|&(name, flag)| {
+                let attached = arg.strip_prefix(name)?;
+                if attached.is_empty() {
+                    args.next().map(|value| (flag, value.as_str()))
+                } else {
+                    Some((flag, attached))
+                }
+            });
+            let (flag, value) = match matched {
+                Some(matched) => matched,
+                None => continue,
+            };
+
+            let dir_list = match flag {
+                Flag::Define => {
+                    macro_options.push(MacroOption::parse_define(value)?);
+                    continue;
+                }
+                Flag::Undefine => {
+                    macro_options.push(MacroOption::parse_undefine(value)?);
+                    continue;
+                }
+                Flag::QuoteDir => &mut quote_dirs,
+                Flag::Dir => &mut dirs,
+                Flag::SystemDir => &mut system_dirs,
+                Flag::AfterDir => &mut after_dirs,
+            };
+            let dir = directory.join(value);
+            if dir.is_dir() {
+                dir_list.push(dir);
+            } else {
+                debug!("Ignoring missing search directory {:?}", dir);
+            }
+        }
+
+        Ok(Self {
+            quote_dirs,
+            dirs,
+            system_dirs,
+            after_dirs,
+            macro_options,
+        })
+    }
+}
+
+/// A parsed `compile_commands.json`.
+#[derive(Debug)]
+pub struct CompilationDatabase {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl CompilationDatabase {
+    pub fn load(path: &Path) -> Result<Self> {
+        let error_context =
+            || format!("Failed to load compilation database \"{}\"", path.display());
+        let content = fs::read_to_string(path).with_context(error_context)?;
+        let mut entries: Vec<Entry> = serde_json::from_str(&content).with_context(error_context)?;
+
+        // Relative directories are resolved against the location of the database
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for entry in &mut entries {
+            entry.directory = base_dir.join(&entry.directory);
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            entries,
+        })
+    }
+
+    /// Returns the flags used to compile the given source file.
+    pub fn flags_for(&self, source_file: &Path) -> Result<Option<CompileFlags>> {
+        let canonical_path = source_file.canonicalize().with_context(|| {
+            format!(
+                "Failed to canonicalize source file path \"{}\"",
+                source_file.display()
+            )
+        })?;
+        let maybe_entry = self.entries.iter().find(|entry| {
+            let file = entry.directory.join(&entry.file);
+            file.canonicalize()
+                .map_or(false, |file| file == canonical_path)
+        });
+        let entry = if let Some(entry) = maybe_entry {
+            entry
+        } else {
+            warn!(
+                "No entry for {:?} in \"{}\"",
+                debug_file_name(source_file),
+                self.path.display()
+            );
+            return Ok(None);
+        };
+
+        let arguments = match (&entry.arguments, &entry.command) {
+            (Some(arguments), _) => arguments.clone(),
+            (None, Some(command)) => split_command(command),
+            (None, None) => Vec::new(),
+        };
+        let flags = CompileFlags::parse(&arguments, &entry.directory)
+            .with_context(|| format!("Failed to parse flags for \"{}\"", source_file.display()))?;
+        debug!(
+            "Flags for {:?} from compilation database: {:#?}",
+            debug_file_name(source_file),
+            flags
+        );
+        Ok(Some(flags))
+    }
+}
@@ -0,0 +1,199 @@
+//! Discovery of the built-in system search directories of a compiler
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    sync::{Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use log::debug;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::env_paths::Language;
+
+/// Name of the file the discovered search directories are cached in, within the cache directory.
+const CACHE_FILE_NAME: &str = "cpp-amalgamate/system-dirs.json";
+
+/// Identifies the output of a compiler, which changes whenever the compiler is replaced.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct CacheKey {
+    path: PathBuf,
+    modified: SystemTime,
+    /// Name of the language, as passed to the compiler
+    language: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    key: CacheKey,
+    dirs: Vec<PathBuf>,
+}
+
+/// Already discovered search directories, loaded from the cache file on first use.
+///
+/// These are kept in memory as well, so that watch mode and building multiple targets only read the
+/// cache file once.
+static CACHE: Lazy<Mutex<HashMap<CacheKey, Vec<PathBuf>>>> =
+    Lazy::new(|| Mutex::new(read_cache_file()));
+
+/// Returns the path of the cache file, in `$XDG_CACHE_HOME`, `%LOCALAPPDATA%`, or `~/.cache`.
+fn cache_file_path() -> Option<PathBuf> {
+    let non_empty_var = |name| env::var_os(name).filter(|value| !value.is_empty());
+    let cache_dir = non_empty_var("XDG_CACHE_HOME")
+        .or_else(|| non_empty_var("LOCALAPPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(non_empty_var("HOME")?).join(".cache")))?;
+    Some(cache_dir.join(CACHE_FILE_NAME))
+}
+
+fn read_cache_file() -> HashMap<CacheKey, Vec<PathBuf>> {
+    let path = match cache_file_path() {
+        Some(path) => path,
+        None => return HashMap::new(),
+    };
+    let entries: Vec<CacheEntry> = match fs::read(&path) {
+        Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|error| {
+            debug!("Ignoring invalid cache file {:?}: {}", path, error);
+            Vec::new()
+        }),
+        Err(error) => {
+            debug!("Failed to read cache file {:?}: {}", path, error);
+            Vec::new()
+        }
+    };
+    entries
+        .into_iter()
+        .map(|entry| (entry.key, entry.dirs))
+        .collect()
+}
+
+/// Writes all cached search directories to the cache file.
+///
+/// Failing to do so only means that the compilers have to be run again next time, so errors are
+/// only logged.
+fn write_cache_file(cache: &HashMap<CacheKey, Vec<PathBuf>>) {
+    let path = match cache_file_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let entries: Vec<_> = cache
+        .iter()
+        .map(|(key, dirs)| CacheEntry {
+            key: key.clone(),
+            dirs: dirs.clone(),
+        })
+        .collect();
+    // Written to a temporary file first, so that concurrent runs never read a partial file
+    let temp_path = path.with_extension(format!("{}.tmp", process::id()));
+    if let Err(error) = write_atomically(&path, &temp_path, &entries) {
+        debug!("Failed to write cache file {:?}: {:#}", path, error);
+        let _ = fs::remove_file(&temp_path);
+    }
+}
+
+fn write_atomically(path: &Path, temp_path: &Path, entries: &[CacheEntry]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(temp_path, serde_json::to_vec(entries)?)?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+const SEARCH_LIST_START: &str = "#include <...> search starts here:";
+const SEARCH_LIST_END: &str = "End of search list.";
+
+/// Finds the compiler executable, searching `PATH` for names without a directory.
+fn find_executable(compiler: &Path) -> Result<PathBuf> {
+    if compiler.components().count() > 1 {
+        return Ok(compiler.to_owned());
+    }
+
+    let path_var = env::var_os("PATH").unwrap_or_default();
+    let maybe_found = env::split_paths(&path_var)
+        .map(|dir| dir.join(compiler))
+        .find(|path| path.is_file());
+    match maybe_found {
+        Some(path) => Ok(path),
+        None => bail!("Failed to find compiler \"{}\"", compiler.display()),
+    }
+}
+
+/// Parses the search list printed by `-v`, which both GCC and Clang write to stderr.
+fn parse_search_list(output: &str) -> Result<Vec<PathBuf>> {
+    let mut lines = output.lines();
+    ensure!(
+        lines.any(|line| line == SEARCH_LIST_START),
+        "Compiler output does not contain a search list"
+    );
+
+    let mut dirs = Vec::new();
+    for line in lines {
+        if line == SEARCH_LIST_END {
+            return Ok(dirs);
+        }
+
+        // Clang on macOS marks framework directories, which are not used for regular includes
+        let dir = line.trim();
+        if !dir.ends_with("(framework directory)") {
+            dirs.push(PathBuf::from(dir));
+        }
+    }
+    bail!("Compiler output contains an unterminated search list")
+}
+
+fn run_compiler(path: &Path, language: Language) -> Result<Vec<PathBuf>> {
+    debug!("Running {:?} to discover its search directories", path);
+    let output = Command::new(path)
+        .args(["-E", "-v", "-x", language.name(), "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output()
+        .context("Failed to run compiler")?;
+    ensure!(
+        output.status.success(),
+        "Compiler exited with {}",
+        output.status
+    );
+    parse_search_list(&String::from_utf8_lossy(&output.stderr))
+}
+
+/// Returns the system search directories the compiler uses for the given language.
+///
+/// The directories are cached across runs for each compiler, keyed by its path and modification
+/// time. Only the entries for the current modification time of a compiler are kept.
+pub fn system_dirs(compiler: &Path, language: Language) -> Result<Vec<PathBuf>> {
+    let error_context = || {
+        format!(
+            "Failed to discover search directories of \"{}\"",
+            compiler.display()
+        )
+    };
+    let path = find_executable(compiler).with_context(error_context)?;
+    let modified = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .with_context(error_context)?;
+    let key = CacheKey {
+        path,
+        modified,
+        language: language.name().to_owned(),
+    };
+
+    let mut cache = CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(dirs) = cache.get(&key) {
+        debug!("Using cached search directories of {:?}", key.path);
+        return Ok(dirs.clone());
+    }
+    let dirs = run_compiler(&key.path, language).with_context(error_context)?;
+    debug!("Search dirs of {:?}: {:#?}", key.path, dirs);
+    // Entries for previous versions of the compiler are never used again
+    cache.retain(|cached, _| cached.path != key.path || cached.modified == key.modified);
+    cache.insert(key, dirs.clone());
+    write_cache_file(&cache);
+    Ok(dirs)
+}
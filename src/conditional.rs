@@ -0,0 +1,927 @@
+//! Evaluation of preprocessor conditionals and tracking of the macros they depend on.
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    ops::Not,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// Whether a conditional branch is taken.
+///
+/// `Unknown` is used for branches whose condition cannot be decided, for example because it
+/// invokes a function-like macro.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Liveness {
+    Dead,
+    Unknown,
+    Live,
+}
+
+impl From<Option<bool>> for Liveness {
+    fn from(value: Option<bool>) -> Self {
+        match value {
+            Some(true) => Self::Live,
+            Some(false) => Self::Dead,
+            None => Self::Unknown,
+        }
+    }
+}
+
+impl Not for Liveness {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        match self {
+            Self::Dead => Self::Live,
+            Self::Unknown => Self::Unknown,
+            Self::Live => Self::Dead,
+        }
+    }
+}
+
+fn split_identifier(s: &str) -> Option<(&str, &str)> {
+    let end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    let identifier = &s[..end];
+    (!identifier.is_empty() && !identifier.starts_with(|c: char| c.is_ascii_digit()))
+        .then(|| (identifier, &s[end..]))
+}
+
+/// Whether a string is a header name enclosed in either quotes or angle brackets.
+pub fn is_header_name(s: &str) -> bool {
+    let delimiters = match s.chars().next() {
+        Some('"') => ('"', '"'),
+        Some('<') => ('<', '>'),
+        _ => return false,
+    };
+    let inner = &s[1..];
+    inner
+        .find(delimiters.1)
+        .map_or(false, |end| end > 0 && end + 1 == inner.len())
+}
+
+/// Parses a line consisting of a single macro name, as used by `#ifdef`, `#ifndef` and `#undef`.
+pub fn parse_macro_name(s: &str) -> Result<String> {
+    match tokenize(s)?.as_slice() {
+        [Token::Ident(name)] => Ok(name.clone()),
+        _ => bail!("expected a single macro name, found \"{}\"", s),
+    }
+}
+
+/// Parses a condition of the form `!defined(NAME)` or `!defined NAME`, returning the macro name.
+pub fn parse_negated_defined(s: &str) -> Option<String> {
+    match tokenize(s).ok()?.as_slice() {
+        [Token::Punct("!"), Token::Ident(defined), Token::Punct("("), Token::Ident(name), Token::Punct(")")]
+        | [Token::Punct("!"), Token::Ident(defined), Token::Ident(name)]
+            if defined == "defined" =>
+        {
+            Some(name.clone())
+        }
+        _ => None,
+    }
+}
+
+/// Returns the identifiers in a condition or the operand of a computed include, except for
+/// `defined`.
+pub fn identifiers(s: &str) -> Vec<String> {
+    tokenize(s)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Ident(name) if name != "defined" => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A macro definition given by either `-D` or a `#define` directive.
+#[derive(Debug, Clone)]
+pub struct MacroDefinition {
+    name: String,
+    function_like: bool,
+    body: String,
+}
+
+impl MacroDefinition {
+    /// Parses the remainder of a `#define` directive following the directive name.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the macro name is not a valid identifier or the parameter list is not closed.
+    pub fn from_directive(s: &str) -> Result<Self> {
+        let (name, rest) = split_identifier(s)
+            .with_context(|| format!("invalid macro name in \"#define {}\"", s))?;
+        let (function_like, body) = if rest.starts_with('(') {
+            let params_end = rest
+                .find(')')
+                .with_context(|| format!("missing ')' in \"#define {}\"", s))?;
+            (true, &rest[(params_end + 1)..])
+        } else {
+            (false, rest)
+        };
+
+        Ok(Self {
+            name: name.to_owned(),
+            function_like,
+            body: body.trim().to_owned(),
+        })
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// A `-D` or `-U` option given on the cli.
+#[derive(Debug, Clone)]
+pub enum MacroOption {
+    /// Define a macro, replacing any previous definition
+    Define(MacroDefinition),
+    /// Remove the definition of a macro
+    Undefine(String),
+}
+
+impl MacroOption {
+    /// Parses the value of a `-D` option, which is either `name`, `name=value`, or
+    /// `name(params)=value`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the macro name is not a valid identifier.
+    pub fn parse_define(s: &str) -> Result<Self> {
+        let (head, body) = s.split_once('=').unwrap_or((s, "1"));
+        let mut definition = MacroDefinition::from_directive(head)?;
+        ensure!(
+            definition.body.is_empty() && !head.ends_with(char::is_whitespace),
+            "invalid macro definition: \"{}\"",
+            s
+        );
+        definition.body = body.to_owned();
+        Ok(Self::Define(definition))
+    }
+
+    /// Parses the value of a `-U` option.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the value is not a valid identifier.
+    pub fn parse_undefine(s: &str) -> Result<Self> {
+        match split_identifier(s) {
+            Some((name, "")) => Ok(Self::Undefine(name.to_owned())),
+            _ => bail!("invalid macro name: \"{}\"", s),
+        }
+    }
+
+    /// Returns the name of the defined or undefined macro.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Define(definition) => definition.name(),
+            Self::Undefine(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MacroState {
+    Object(String),
+    Function,
+    /// Known not to be defined, either by `-U` or an `#undef` directive
+    Undefined,
+    Unknown,
+}
+
+/// The macros defined so far, as far as they are known.
+///
+/// Macros which were never defined or undefined by `-D`/`-U` or a directive are in an unknown
+/// state, as they might be predefined by the compiler (such as `__cplusplus` or `_WIN32`) or defined
+/// by a header which is not inlined.
+#[derive(Debug, Clone, Default)]
+pub struct Macros {
+    states: HashMap<String, MacroState>,
+}
+
+impl Macros {
+    pub fn new(options: impl IntoIterator<Item = MacroOption>) -> Self {
+        let mut macros = Self::default();
+        for option in options {
+            macros.apply(option);
+        }
+        macros
+    }
+
+    pub fn apply(&mut self, option: MacroOption) {
+        match option {
+            MacroOption::Define(definition) => self.define(definition),
+            MacroOption::Undefine(name) => self.undefine(&name),
+        }
+    }
+
+    pub fn define(&mut self, definition: MacroDefinition) {
+        let state = if definition.function_like {
+            MacroState::Function
+        } else {
+            MacroState::Object(definition.body)
+        };
+        self.states.insert(definition.name, state);
+    }
+
+    /// Takes over all macros of `other`, replacing any previous definitions.
+    pub fn extend(&mut self, other: Self) {
+        self.states.extend(other.states);
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.states.insert(name.to_owned(), MacroState::Undefined);
+    }
+
+    /// Marks a macro as not defined, unless anything is known about it already.
+    ///
+    /// This is used for header guard macros, which are assumed not to be predefined.
+    pub fn assume_undefined(&mut self, name: &str) {
+        self.states
+            .entry(name.to_owned())
+            .or_insert(MacroState::Undefined);
+    }
+
+    /// Marks a macro as possibly (un-)defined.
+    ///
+    /// This is used for `#define` and `#undef` directives in branches that might or might not be
+    /// taken.
+    pub fn forget(&mut self, name: &str) {
+        self.states.insert(name.to_owned(), MacroState::Unknown);
+    }
+
+    /// Returns whether a macro is in the same state in both sets of macros.
+    pub fn agrees_on(&self, other: &Self, name: &str) -> bool {
+        self.states.get(name) == other.states.get(name)
+    }
+
+    pub fn is_defined(&self, name: &str) -> Liveness {
+        if HAS_INCLUDE_NAMES.contains(&name) {
+            return Liveness::Live;
+        }
+        match self.states.get(name) {
+            Some(MacroState::Object(_) | MacroState::Function) => Liveness::Live,
+            Some(MacroState::Undefined) => Liveness::Dead,
+            Some(MacroState::Unknown) | None => Liveness::Unknown,
+        }
+    }
+
+    /// Expands the operand of a computed include such as `#include CONFIG_HEADER`.
+    ///
+    /// Only operands consisting of a single object-like macro are supported, which has to expand
+    /// to a header name, possibly via further such macros. Returns `None` otherwise, or if any of
+    /// the macros is in an unknown state.
+    pub fn expand_header_name(&self, operand: &str) -> Option<String> {
+        let mut current = operand.trim();
+        let mut expanded_names = Vec::new();
+        while !is_header_name(current) {
+            let name = match split_identifier(current) {
+                Some((name, "")) if !expanded_names.contains(&name) => name,
+                _ => return None,
+            };
+            expanded_names.push(name);
+            current = match self.states.get(name)? {
+                MacroState::Object(body) => body.trim(),
+                MacroState::Function | MacroState::Undefined | MacroState::Unknown => return None,
+            };
+        }
+        Some(current.to_owned())
+    }
+
+    /// Evaluates the condition of an `#if` or `#elif` directive.
+    ///
+    /// Identifiers which are known not to be defined as macros evaluate to 0, just as they do for
+    /// the compiler. Invocations of function-like macros and identifiers in an unknown state make
+    /// the condition undecidable, unless short-circuiting makes their value irrelevant.
+    ///
+    /// `__has_include` and `__has_include_next` are evaluated using `has_include`, which is passed
+    /// the header name including its quotes or angle brackets, and whether the call is
+    /// `__has_include_next`.
+    pub fn evaluate(
+        &self,
+        condition: &str,
+        mut has_include: impl FnMut(&str, bool) -> Result<Liveness>,
+    ) -> Result<Liveness> {
+        let tokens = self.expand(tokenize(condition)?, &mut has_include)?;
+        let expr = Parser { tokens, pos: 0 }.parse()?;
+        Ok(Liveness::from(expr.evaluate()?.map(Value::is_true)))
+    }
+
+    fn expand(
+        &self,
+        tokens: Vec<Token>,
+        has_include: &mut impl FnMut(&str, bool) -> Result<Liveness>,
+    ) -> Result<Vec<Token>> {
+        let mut pending = tokens;
+        pending.reverse();
+        let mut active = Vec::new();
+        let mut expanded = Vec::new();
+
+        while let Some(token) = next_token(&mut pending, &mut active) {
+            let name = match token {
+                Token::Ident(name) => name,
+                Token::HasInclude { header_name, next } => {
+                    expanded.push(Token::from(has_include(&header_name, next)?));
+                    continue;
+                }
+                token => {
+                    expanded.push(token);
+                    continue;
+                }
+            };
+
+            if name == "defined" {
+                let parenthesized =
+                    peek_token(&mut pending, &mut active) == Some(&Token::Punct("("));
+                if parenthesized {
+                    pending.pop();
+                }
+                let operand = match next_token(&mut pending, &mut active) {
+                    Some(Token::Ident(operand)) => operand,
+                    _ => bail!("expected macro name after \"defined\""),
+                };
+                if parenthesized {
+                    ensure!(
+                        next_token(&mut pending, &mut active) == Some(Token::Punct(")")),
+                        "missing ')' after \"defined({}\"",
+                        operand
+                    );
+                }
+                expanded.push(Token::from(self.is_defined(&operand)));
+                continue;
+            }
+
+            // Macros are not expanded within their own expansion, and evaluate to 0 there
+            let state = if active.contains(&name) {
+                Some(&MacroState::Undefined)
+            } else {
+                self.states.get(&name)
+            };
+            let is_call = peek_token(&mut pending, &mut active) == Some(&Token::Punct("("));
+            match state {
+                Some(MacroState::Object(body)) => {
+                    pending.push(Token::EndOfExpansion);
+                    pending.extend(tokenize(body)?.into_iter().rev());
+                    active.push(name);
+                }
+                // Unknown identifiers followed by parentheses are most likely compiler builtins such
+                // as __has_builtin, whose value cannot be known.
+                Some(MacroState::Function | MacroState::Undefined) | None if is_call => {
+                    skip_arguments(&mut pending, &mut active)?;
+                    expanded.push(Token::Unknown);
+                }
+                Some(MacroState::Unknown) => {
+                    if is_call {
+                        skip_arguments(&mut pending, &mut active)?;
+                    }
+                    expanded.push(Token::Unknown);
+                }
+                // Identifiers which were never defined might be predefined by the compiler
+                None if !matches!(name.as_str(), "true" | "false") => {
+                    expanded.push(Token::Unknown);
+                }
+                Some(MacroState::Function | MacroState::Undefined) | None => {
+                    let value = i64::from(name == "true");
+                    expanded.push(Token::Number(Value::signed(value)));
+                }
+            }
+        }
+
+        Ok(expanded)
+    }
+}
+
+/// Pops the next token, keeping track of which macro expansions have ended.
+fn next_token(pending: &mut Vec<Token>, active: &mut Vec<String>) -> Option<Token> {
+    while pending.last() == Some(&Token::EndOfExpansion) {
+        pending.pop();
+        active.pop();
+    }
+    pending.pop()
+}
+
+fn peek_token<'a>(pending: &'a mut Vec<Token>, active: &mut Vec<String>) -> Option<&'a Token> {
+    while pending.last() == Some(&Token::EndOfExpansion) {
+        pending.pop();
+        active.pop();
+    }
+    pending.last()
+}
+
+fn skip_arguments(pending: &mut Vec<Token>, active: &mut Vec<String>) -> Result<()> {
+    let mut depth = 0_usize;
+    loop {
+        match next_token(pending, active) {
+            Some(Token::Punct("(")) => depth += 1,
+            Some(Token::Punct(")")) => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(());
+                }
+            }
+            Some(_) => {}
+            None => bail!("unterminated macro argument list"),
+        }
+    }
+}
+
+/// An integer value in a preprocessor expression, either of type `intmax_t` or `uintmax_t`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Value {
+    bits: i64,
+    unsigned: bool,
+}
+
+// The casts between i64 and u64 reinterpret the bits, as the C++ conversions do.
+#[allow(clippy::cast_sign_loss, clippy::cast_possible_wrap)]
+impl Value {
+    fn signed(bits: i64) -> Self {
+        Self {
+            bits,
+            unsigned: false,
+        }
+    }
+
+    fn is_true(self) -> bool {
+        self.bits != 0
+    }
+
+    fn unary(self, op: &str) -> Self {
+        match op {
+            "-" => Self {
+                bits: self.bits.wrapping_neg(),
+                ..self
+            },
+            "~" => Self {
+                bits: !self.bits,
+                ..self
+            },
+            "!" => Self::signed(i64::from(!self.is_true())),
+            _ => self,
+        }
+    }
+
+    fn binary(self, op: &str, rhs: Self) -> Result<Self> {
+        let unsigned = self.unsigned || rhs.unsigned;
+        let (lhs_bits, rhs_bits) = (self.bits, rhs.bits);
+        let (lhs_unsigned, rhs_unsigned) = (lhs_bits as u64, rhs_bits as u64);
+        let ordering = if unsigned {
+            lhs_unsigned.cmp(&rhs_unsigned)
+        } else {
+            lhs_bits.cmp(&rhs_bits)
+        };
+
+        let bits = match op {
+            "+" => lhs_bits.wrapping_add(rhs_bits),
+            "-" => lhs_bits.wrapping_sub(rhs_bits),
+            "*" => lhs_bits.wrapping_mul(rhs_bits),
+            "/" | "%" => {
+                ensure!(rhs_bits != 0, "division by zero");
+                match (op, unsigned) {
+                    ("/", true) => (lhs_unsigned / rhs_unsigned) as i64,
+                    ("/", false) => lhs_bits.wrapping_div(rhs_bits),
+                    (_, true) => (lhs_unsigned % rhs_unsigned) as i64,
+                    (_, false) => lhs_bits.wrapping_rem(rhs_bits),
+                }
+            }
+            // Shifts have the type of their left operand.
+            "<<" | ">>" => {
+                let amount = if rhs.unsigned || rhs_bits >= 0 {
+                    u32::try_from(rhs_unsigned)
+                        .ok()
+                        .filter(|&amount| amount < 64)
+                } else {
+                    None
+                };
+                let bits = match (op, amount) {
+                    ("<<", Some(amount)) => lhs_bits << amount,
+                    (_, Some(amount)) if self.unsigned => (lhs_unsigned >> amount) as i64,
+                    (_, Some(amount)) => lhs_bits >> amount,
+                    (_, None) if op == ">>" && !self.unsigned && lhs_bits < 0 => -1,
+                    (_, None) => 0,
+                };
+                return Ok(Self { bits, ..self });
+            }
+            "<" | ">" | "<=" | ">=" | "==" | "!=" => {
+                let result = match op {
+                    "<" => ordering == Ordering::Less,
+                    ">" => ordering == Ordering::Greater,
+                    "<=" => ordering != Ordering::Greater,
+                    ">=" => ordering != Ordering::Less,
+                    "==" => ordering == Ordering::Equal,
+                    _ => ordering != Ordering::Equal,
+                };
+                return Ok(Self::signed(i64::from(result)));
+            }
+            "&" => lhs_bits & rhs_bits,
+            "^" => lhs_bits ^ rhs_bits,
+            "|" => lhs_bits | rhs_bits,
+            _ => bail!("unsupported operator \"{}\"", op),
+        };
+        Ok(Self { bits, unsigned })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(Value),
+    Punct(&'static str),
+    /// String literals are not valid in expressions, but may appear as builtin arguments.
+    Str,
+    /// A `__has_include` or `__has_include_next` with a header name as its argument.
+    HasInclude {
+        header_name: String,
+        next: bool,
+    },
+    /// Placeholder for a value that cannot be determined.
+    Unknown,
+    /// Marks the end of a macro expansion, only used while expanding.
+    EndOfExpansion,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ident(name) => write!(f, "'{}'", name),
+            Self::Number(value) => write!(f, "'{}'", value.bits),
+            Self::Punct(punct) => write!(f, "'{}'", punct),
+            Self::Str => write!(f, "string literal"),
+            Self::HasInclude { next: false, .. } => write!(f, "'__has_include'"),
+            Self::HasInclude { next: true, .. } => write!(f, "'__has_include_next'"),
+            Self::Unknown | Self::EndOfExpansion => write!(f, "undecidable value"),
+        }
+    }
+}
+
+impl From<Liveness> for Token {
+    fn from(liveness: Liveness) -> Self {
+        match liveness {
+            Liveness::Dead => Self::Number(Value::signed(0)),
+            Liveness::Unknown => Self::Unknown,
+            Liveness::Live => Self::Number(Value::signed(1)),
+        }
+    }
+}
+
+const PUNCTUATORS: [&str; 25] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "(", ")", "!", "~", "+", "-", "*", "/", "%",
+    "<", ">", "&", "^", "|", "?", ":", ",",
+];
+
+const ALTERNATIVE_TOKENS: [(&str, &str); 8] = [
+    ("and", "&&"),
+    ("or", "||"),
+    ("not", "!"),
+    ("not_eq", "!="),
+    ("bitand", "&"),
+    ("bitor", "|"),
+    ("xor", "^"),
+    ("compl", "~"),
+];
+
+const HAS_INCLUDE_NAMES: [&str; 2] = ["__has_include", "__has_include_next"];
+
+/// Splits off a parenthesized header name, such as `(<vector>)`, returning it without the
+/// parentheses.
+///
+/// Header names are not regular tokens, so they need to be split off before tokenizing the rest.
+fn split_header_name_argument(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start().strip_prefix('(')?.trim_start();
+    let closing = match s.chars().next()? {
+        '<' => '>',
+        '"' => '"',
+        _ => return None,
+    };
+    let end = s[1..].find(closing)? + 2;
+    let rest = s[end..].trim_start().strip_prefix(')')?;
+    Some((&s[..end], rest))
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = s;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() || rest.starts_with("//") {
+            return Ok(tokens);
+        }
+        if let Some(tail) = rest.strip_prefix("/*") {
+            rest = tail.find("*/").map_or("", |end| &tail[(end + 2)..]);
+            continue;
+        }
+
+        let bytes = rest.as_bytes();
+        let starts_number = bytes[0].is_ascii_digit()
+            || (bytes[0] == b'.' && bytes.get(1).map_or(false, u8::is_ascii_digit));
+        if starts_number {
+            let end = pp_number_len(bytes);
+            tokens.push(Token::Number(parse_integer(&rest[..end])?));
+            rest = &rest[end..];
+        } else if let Some((identifier, tail)) = split_identifier(rest) {
+            if matches!(identifier, "L" | "u" | "U" | "u8") && tail.starts_with('\'') {
+                let (value, tail) = parse_char(tail)?;
+                tokens.push(Token::Number(value));
+                rest = tail;
+                continue;
+            }
+
+            if HAS_INCLUDE_NAMES.contains(&identifier) {
+                if let Some((header_name, tail)) = split_header_name_argument(tail) {
+                    tokens.push(Token::HasInclude {
+                        header_name: header_name.to_owned(),
+                        next: identifier == "__has_include_next",
+                    });
+                    rest = tail;
+                    continue;
+                }
+            }
+
+            let alternative = ALTERNATIVE_TOKENS
+                .iter()
+                .find(|(name, _)| *name == identifier);
+            tokens.push(alternative.map_or_else(
+                || Token::Ident(identifier.to_owned()),
+                |(_, punct)| Token::Punct(punct),
+            ));
+            rest = tail;
+        } else if rest.starts_with('\'') {
+            let (value, tail) = parse_char(rest)?;
+            tokens.push(Token::Number(value));
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix('"') {
+            let end = find_closing_quote(tail, '"')
+                .with_context(|| format!("unterminated string literal in \"{}\"", s))?;
+            tokens.push(Token::Str);
+            rest = &tail[(end + 1)..];
+        } else if let Some(punct) = PUNCTUATORS.iter().find(|punct| rest.starts_with(**punct)) {
+            tokens.push(Token::Punct(punct));
+            rest = &rest[punct.len()..];
+        } else {
+            let c = rest.chars().next().unwrap_or_default();
+            bail!("unexpected character '{}' in \"{}\"", c, s);
+        }
+    }
+}
+
+fn pp_number_len(bytes: &[u8]) -> usize {
+    let mut len = 1;
+    while let Some(&byte) = bytes.get(len) {
+        let prev = bytes[len - 1];
+        let continues = byte.is_ascii_alphanumeric()
+            || byte == b'_'
+            || byte == b'.'
+            || (byte == b'\'' && bytes.get(len + 1).map_or(false, u8::is_ascii_alphanumeric))
+            || (matches!(byte, b'+' | b'-') && matches!(prev, b'e' | b'E' | b'p' | b'P'));
+        if !continues {
+            break;
+        }
+        len += 1;
+    }
+    len
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn parse_integer(literal: &str) -> Result<Value> {
+    let normalized: String = literal
+        .chars()
+        .filter(|&c| c != '\'')
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let (digits, radix) = if let Some(digits) = normalized.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = normalized.strip_prefix("0b") {
+        (digits, 2)
+    } else if let Some(digits) = normalized.strip_prefix('0') {
+        (digits, 8)
+    } else {
+        (normalized.as_str(), 10)
+    };
+
+    let digits_end = digits
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or(digits.len());
+    let (digits, suffix) = digits.split_at(digits_end);
+    let unsigned = match suffix {
+        "" | "l" | "ll" | "z" => false,
+        "u" | "ul" | "lu" | "ull" | "llu" | "uz" | "zu" => true,
+        _ => bail!("invalid integer literal \"{}\"", literal),
+    };
+    let value = if digits.is_empty() {
+        ensure!(radix == 8, "invalid integer literal \"{}\"", literal);
+        0
+    } else {
+        u64::from_str_radix(digits, radix)
+            .with_context(|| format!("integer literal \"{}\" is too large", literal))?
+    };
+
+    Ok(Value {
+        bits: value as i64,
+        unsigned: unsigned || i64::try_from(value).is_err(),
+    })
+}
+
+fn find_closing_quote(s: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    s.char_indices().find_map(|(idx, c)| {
+        let is_end = c == quote && !escaped;
+        escaped = c == '\\' && !escaped;
+        is_end.then(|| idx)
+    })
+}
+
+/// Parses a character literal starting with `'`, returning its value and the remaining input.
+fn parse_char(s: &str) -> Result<(Value, &str)> {
+    let content = &s[1..];
+    let end = find_closing_quote(content, '\'')
+        .with_context(|| format!("unterminated character literal {}", s))?;
+    let literal = &content[..end];
+
+    let value = if let Some(escape) = literal.strip_prefix('\\') {
+        let mut chars = escape.chars();
+        match chars.next() {
+            Some('x') => u32::from_str_radix(chars.as_str(), 16).ok(),
+            Some('0'..='7') => u32::from_str_radix(escape, 8).ok(),
+            Some(c) if chars.as_str().is_empty() => match c {
+                'n' => Some(0x0a),
+                't' => Some(0x09),
+                'r' => Some(0x0d),
+                'a' => Some(0x07),
+                'b' => Some(0x08),
+                'f' => Some(0x0c),
+                'v' => Some(0x0b),
+                '\\' | '\'' | '"' | '?' => Some(u32::from(c)),
+                _ => None,
+            },
+            _ => None,
+        }
+    } else {
+        let mut chars = literal.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(u32::from(c)),
+            _ => None,
+        }
+    };
+
+    let value = value.with_context(|| format!("unsupported character literal '{}'", literal))?;
+    Ok((Value::signed(i64::from(value)), &content[(end + 1)..]))
+}
+
+#[derive(Debug)]
+enum Expr {
+    Value(Option<Value>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluates the expression, with `None` representing an unknown value.
+    fn evaluate(&self) -> Result<Option<Value>> {
+        Ok(match self {
+            Self::Value(value) => *value,
+            Self::Unary(op, operand) => operand.evaluate()?.map(|value| value.unary(op)),
+            Self::Binary(op @ ("&&" | "||"), lhs, rhs) => {
+                // The value which decides the result on its own
+                let decisive = *op == "||";
+                let lhs = lhs.evaluate()?.map(Value::is_true);
+                if lhs == Some(decisive) {
+                    Some(Value::signed(i64::from(decisive)))
+                } else {
+                    let rhs = rhs.evaluate()?.map(Value::is_true);
+                    match (lhs, rhs) {
+                        (_, Some(value)) if value == decisive => Some(value),
+                        (Some(_), Some(value)) => Some(value),
+                        _ => None,
+                    }
+                    .map(|value| Value::signed(i64::from(value)))
+                }
+            }
+            Self::Binary(op, lhs, rhs) => match (lhs.evaluate()?, rhs.evaluate()?) {
+                (Some(lhs), Some(rhs)) => Some(lhs.binary(op, rhs)?),
+                _ => None,
+            },
+            Self::Conditional(condition, if_true, if_false) => {
+                match condition.evaluate()?.map(Value::is_true) {
+                    Some(true) => if_true.evaluate()?,
+                    Some(false) => if_false.evaluate()?,
+                    None => {
+                        let if_true = if_true.evaluate()?;
+                        (if_true == if_false.evaluate()?).then(|| if_true).flatten()
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+fn binary_precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "*" | "/" | "%" => 10,
+        "+" | "-" => 9,
+        "<<" | ">>" => 8,
+        "<" | ">" | "<=" | ">=" => 7,
+        "==" | "!=" => 6,
+        "&" => 5,
+        "^" => 4,
+        "|" => 3,
+        "&&" => 2,
+        "||" => 1,
+        _ => return None,
+    })
+}
+
+impl Parser {
+    fn parse(mut self) -> Result<Expr> {
+        ensure!(!self.tokens.is_empty(), "missing expression");
+        let expr = self.parse_conditional()?;
+        ensure!(
+            self.pos == self.tokens.len(),
+            "unexpected {} in expression",
+            self.tokens[self.pos]
+        );
+        Ok(expr)
+    }
+
+    fn peek_punct(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Punct(punct)) => Some(*punct),
+            _ => None,
+        }
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Result<()> {
+        ensure!(
+            self.peek_punct() == Some(punct),
+            "expected '{}' in expression",
+            punct
+        );
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expr> {
+        let condition = self.parse_binary(1)?;
+        if self.peek_punct() != Some("?") {
+            return Ok(condition);
+        }
+
+        self.pos += 1;
+        let if_true = self.parse_conditional()?;
+        self.expect_punct(":")?;
+        let if_false = self.parse_conditional()?;
+        Ok(Expr::Conditional(
+            Box::new(condition),
+            Box::new(if_true),
+            Box::new(if_false),
+        ))
+    }
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(op) = self.peek_punct() {
+            match binary_precedence(op) {
+                Some(precedence) if precedence >= min_precedence => {
+                    self.pos += 1;
+                    let rhs = self.parse_binary(precedence + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                }
+                _ => break,
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .context("unexpected end of expression")?;
+        self.pos += 1;
+        match token {
+            Token::Punct(op @ ("+" | "-" | "!" | "~")) => {
+                Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+            }
+            Token::Punct("(") => {
+                let expr = self.parse_conditional()?;
+                self.expect_punct(")")?;
+                Ok(expr)
+            }
+            Token::Number(value) => Ok(Expr::Value(Some(*value))),
+            Token::Unknown => Ok(Expr::Value(None)),
+            token => bail!("unexpected {} in expression", token),
+        }
+    }
+}
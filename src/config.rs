@@ -0,0 +1,331 @@
+//! Discovery and parsing of the project configuration file
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, ensure, Context, Error, Result};
+use cpp_amalgamate::{
+    Amalgamator, AnnotationLevel, CommentStripping, ErrorHandling, GraphFormat, InvertibleGlob,
+    Language, LineDirectiveStyle, MacroOption, PathPrefixRemap,
+};
+use log::{debug, info};
+use serde::{de, Deserialize, Deserializer};
+
+pub const FILE_NAME: &str = "cpp-amalgamate.toml";
+
+fn from_str_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+fn from_str_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Value of settings such as `line-directives`, which are either a boolean or the variant to use.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SwitchConfig {
+    Enabled(bool),
+    Variant(String),
+}
+
+impl SwitchConfig {
+    /// Returns the variant, with `true` and `false` standing for `enabled` and `disabled`.
+    fn variant<T: FromStr<Err = Error>>(&self, enabled: T, disabled: T) -> Result<T> {
+        match self {
+            Self::Enabled(true) => Ok(enabled),
+            Self::Enabled(false) => Ok(disabled),
+            Self::Variant(variant) => variant.parse(),
+        }
+    }
+}
+
+/// Settings of a single target, or the defaults shared by all targets.
+///
+/// The keys are named after the corresponding cli flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct TargetConfig {
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    dep_file: Option<PathBuf>,
+    dep_target: Option<String>,
+    dep_phony: Option<bool>,
+    graph: Option<PathBuf>,
+    #[serde(deserialize_with = "from_str_option")]
+    graph_format: Option<GraphFormat>,
+    source_map: Option<PathBuf>,
+    dir: Vec<PathBuf>,
+    dir_quote: Vec<PathBuf>,
+    dir_system: Vec<PathBuf>,
+    compile_commands: Option<PathBuf>,
+    use_env_paths: Option<bool>,
+    system_dirs_from: Option<PathBuf>,
+    #[serde(deserialize_with = "from_str_option")]
+    language: Option<Language>,
+    #[serde(deserialize_with = "from_str_list")]
+    filter: Vec<InvertibleGlob>,
+    #[serde(deserialize_with = "from_str_list")]
+    filter_quote: Vec<InvertibleGlob>,
+    #[serde(deserialize_with = "from_str_list")]
+    filter_system: Vec<InvertibleGlob>,
+    #[serde(deserialize_with = "from_str_list")]
+    multi_include: Vec<InvertibleGlob>,
+    multi_include_unguarded: Option<bool>,
+    define: Vec<String>,
+    undefine: Vec<String>,
+    #[serde(deserialize_with = "from_str_option")]
+    unresolvable_include: Option<ErrorHandling>,
+    #[serde(deserialize_with = "from_str_option")]
+    unresolvable_quote_include: Option<ErrorHandling>,
+    #[serde(deserialize_with = "from_str_option")]
+    unresolvable_system_include: Option<ErrorHandling>,
+    #[serde(deserialize_with = "from_str_option")]
+    cyclic_include: Option<ErrorHandling>,
+    line_directives: Option<SwitchConfig>,
+    line_directive_base: Option<PathBuf>,
+    #[serde(deserialize_with = "from_str_list")]
+    remap_path_prefix: Vec<PathPrefixRemap>,
+    annotate: Option<SwitchConfig>,
+    strip_comments: Option<SwitchConfig>,
+    strip_header_guards: Option<bool>,
+}
+
+impl TargetConfig {
+    /// Applies these settings, resolving relative paths against `base_dir`.
+    ///
+    /// Lists are appended to those already configured, so search directories given on the cli
+    /// are added before applying this. Other settings replace those already configured.
+    fn apply(&self, base_dir: &Path, amalgamator: &mut Amalgamator) -> Result<()> {
+        amalgamator.inputs(self.inputs.iter().map(|path| base_dir.join(path)));
+        for dir in &self.dir {
+            amalgamator.search_dir(base_dir.join(dir));
+        }
+        for dir in &self.dir_quote {
+            amalgamator.quote_search_dir(base_dir.join(dir));
+        }
+        for dir in &self.dir_system {
+            amalgamator.system_search_dir(base_dir.join(dir));
+        }
+        if let Some(path) = &self.compile_commands {
+            amalgamator.compile_commands(base_dir.join(path));
+        }
+        for glob in &self.filter {
+            amalgamator.filter(glob.clone());
+        }
+        for glob in &self.filter_quote {
+            amalgamator.quote_filter(glob.clone());
+        }
+        for glob in &self.filter_system {
+            amalgamator.system_filter(glob.clone());
+        }
+        for glob in &self.multi_include {
+            amalgamator.multi_include(glob.clone());
+        }
+        for remap in &self.remap_path_prefix {
+            amalgamator.remap_path_prefix(remap.clone());
+        }
+        for define in &self.define {
+            amalgamator.macro_option(MacroOption::parse_define(define)?);
+        }
+        for undefine in &self.undefine {
+            amalgamator.macro_option(MacroOption::parse_undefine(undefine)?);
+        }
+
+        if let Some(enabled) = self.use_env_paths {
+            amalgamator.use_env_paths(enabled);
+        }
+        if let Some(compiler) = &self.system_dirs_from {
+            // Plain names such as g++ are searched in PATH instead
+            if compiler.components().count() > 1 {
+                amalgamator.system_dirs_from(base_dir.join(compiler));
+            } else {
+                amalgamator.system_dirs_from(compiler);
+            }
+        }
+        if let Some(language) = self.language {
+            amalgamator.language(language);
+        }
+        if let Some(enabled) = self.multi_include_unguarded {
+            amalgamator.multi_include_unguarded(enabled);
+        }
+        if let Some(handling) = self.unresolvable_include {
+            amalgamator.unresolvable_include(handling);
+        }
+        if let Some(handling) = self.unresolvable_quote_include {
+            amalgamator.unresolvable_quote_include(handling);
+        }
+        if let Some(handling) = self.unresolvable_system_include {
+            amalgamator.unresolvable_system_include(handling);
+        }
+        if let Some(handling) = self.cyclic_include {
+            amalgamator.cyclic_include(handling);
+        }
+        match &self.line_directives {
+            Some(SwitchConfig::Enabled(enabled)) => {
+                amalgamator.line_directives(*enabled);
+            }
+            Some(SwitchConfig::Variant(style)) => {
+                let style: LineDirectiveStyle = style.parse()?;
+                amalgamator
+                    .line_directives(true)
+                    .line_directive_style(style);
+            }
+            None => {}
+        }
+        if let Some(dir) = &self.line_directive_base {
+            amalgamator.line_directive_base(base_dir.join(dir));
+        }
+        if let Some(annotate) = &self.annotate {
+            amalgamator
+                .annotate(annotate.variant(AnnotationLevel::Includes, AnnotationLevel::None)?);
+        }
+        if let Some(strip_comments) = &self.strip_comments {
+            amalgamator.strip_comments(
+                strip_comments.variant(CommentStripping::All, CommentStripping::None)?,
+            );
+        }
+        if let Some(enabled) = self.strip_header_guards {
+            amalgamator.strip_header_guards(enabled);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    defaults: TargetConfig,
+    targets: BTreeMap<String, TargetConfig>,
+}
+
+/// Where to write the amalgamated file, its dependency file, its include graph, and its source map.
+#[derive(Debug, Default)]
+pub struct Outputs {
+    pub output: Option<PathBuf>,
+    pub dep_file: Option<PathBuf>,
+    pub dep_target: Option<String>,
+    pub dep_phony: bool,
+    pub graph: Option<PathBuf>,
+    pub graph_format: Option<GraphFormat>,
+    pub source_map: Option<PathBuf>,
+}
+
+/// A parsed `cpp-amalgamate.toml`, declaring defaults and named targets.
+#[derive(Debug)]
+pub struct Config {
+    path: PathBuf,
+    /// Directory containing the configuration file, which relative paths are resolved against
+    base_dir: PathBuf,
+    file: ConfigFile,
+}
+
+impl Config {
+    /// Searches the current directory and its parents for a configuration file.
+    pub fn discover() -> Result<Option<Self>> {
+        let current_dir = env::current_dir().context("Failed to determine current directory")?;
+        for dir in current_dir.ancestors() {
+            let path = dir.join(FILE_NAME);
+            if path.is_file() {
+                info!("Using configuration file {:?}", path);
+                return Self::load(&path).map(Some);
+            }
+        }
+
+        debug!("No {} found", FILE_NAME);
+        Ok(None)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let error_context = || format!("Failed to load configuration file \"{}\"", path.display());
+        let content = fs::read_to_string(path).with_context(error_context)?;
+        let file: ConfigFile = toml::from_str(&content).with_context(error_context)?;
+        let defaults = &file.defaults;
+        ensure!(
+            defaults.inputs.is_empty()
+                && defaults.output.is_none()
+                && defaults.dep_file.is_none()
+                && defaults.dep_target.is_none()
+                && defaults.graph.is_none()
+                && defaults.source_map.is_none(),
+            "inputs, output, dep-file, dep-target, graph and source-map cannot be given as defaults \
+             in \"{}\"",
+            path.display()
+        );
+
+        let base_dir = path.parent().map_or_else(PathBuf::new, Path::to_owned);
+        Ok(Self {
+            path: path.to_owned(),
+            base_dir,
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the names of all targets in alphabetical order.
+    pub fn target_names(&self) -> impl Iterator<Item = &str> {
+        self.file.targets.keys().map(String::as_str)
+    }
+
+    /// Applies the defaults, returning the default outputs.
+    pub fn apply_defaults(&self, amalgamator: &mut Amalgamator) -> Result<Outputs> {
+        self.file.defaults.apply(&self.base_dir, amalgamator)?;
+        Ok(Outputs {
+            dep_phony: self.file.defaults.dep_phony.unwrap_or(false),
+            graph_format: self.file.defaults.graph_format,
+            ..Outputs::default()
+        })
+    }
+
+    /// Applies the defaults followed by the settings of the given target.
+    ///
+    /// Returns the files the target should be written to.
+    pub fn apply_target(&self, name: &str, amalgamator: &mut Amalgamator) -> Result<Outputs> {
+        let target = match self.file.targets.get(name) {
+            Some(target) => target,
+            None => bail!("Unknown target '{}'", name),
+        };
+        ensure!(!target.inputs.is_empty(), "Target '{}' has no inputs", name);
+
+        let defaults = self.apply_defaults(amalgamator)?;
+        target.apply(&self.base_dir, amalgamator)?;
+        Ok(Outputs {
+            output: target.output.as_ref().map(|path| self.base_dir.join(path)),
+            dep_file: target
+                .dep_file
+                .as_ref()
+                .map(|path| self.base_dir.join(path)),
+            dep_target: target.dep_target.clone(),
+            dep_phony: target.dep_phony.unwrap_or(defaults.dep_phony),
+            graph: target.graph.as_ref().map(|path| self.base_dir.join(path)),
+            graph_format: target.graph_format.or(defaults.graph_format),
+            source_map: target
+                .source_map
+                .as_ref()
+                .map(|path| self.base_dir.join(path)),
+        })
+    }
+}
@@ -0,0 +1,86 @@
+//! Search directories from the environment variables used by GCC and Clang
+use std::{
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Error, Result};
+use log::debug;
+
+/// Language of the processed source files, which selects the environment variables used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    C,
+    Cxx,
+}
+
+impl Language {
+    pub const NAMES: [&'static str; 2] = ["c", "c++"];
+
+    /// Name of the language as accepted by the `-x` flag of GCC and Clang.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::C => "c",
+            Self::Cxx => "c++",
+        }
+    }
+
+    /// Name of the variable listing search directories for this language only.
+    pub(crate) fn include_path_var(self) -> &'static str {
+        match self {
+            Self::C => "C_INCLUDE_PATH",
+            Self::Cxx => "CPLUS_INCLUDE_PATH",
+        }
+    }
+}
+
+impl Default for Language {
+    fn default() -> Self {
+        Self::Cxx
+    }
+}
+
+impl FromStr for Language {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "c" => Self::C,
+            "c++" => Self::Cxx,
+            _ => bail!("Invalid language: \"{}\"", s),
+        })
+    }
+}
+
+/// Returns the search directories listed in an environment variable such as `CPATH`.
+///
+/// Just like for GCC, an empty entry stands for the current directory, and directories which do
+/// not exist are ignored. If the variable is unset or empty, no directories are returned.
+pub fn search_dirs(var: &str) -> Vec<PathBuf> {
+    let value = match env::var_os(var) {
+        Some(value) if !value.is_empty() => value,
+        _ => {
+            debug!("{} is not set", var);
+            return Vec::new();
+        }
+    };
+    let dirs: Vec<_> = env::split_paths(&value)
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                Path::new(".").to_owned()
+            } else {
+                dir
+            }
+        })
+        .filter(|dir| {
+            let exists = dir.is_dir();
+            if !exists {
+                debug!("Ignoring missing search directory {:?} from {}", dir, var);
+            }
+            exists
+        })
+        .collect();
+    debug!("Search dirs from {}: {:#?}", var, dirs);
+    dirs
+}
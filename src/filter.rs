@@ -59,33 +59,44 @@ fn build_set_and_infos(
 
     if log_enabled!(Level::Debug) {
         let glob_strs: Vec<_> = infos.iter().map(|info| info.str.clone()).collect();
-        debug!("{} ignore globs: {:?}", type_name, glob_strs);
+        debug!("{} globs: {:?}", type_name, glob_strs);
     }
 
     Ok((set_builder.build()?, infos))
 }
 
-fn check_should_inline(
+/// Returns the last glob matching the path, which takes precedence over all others.
+fn last_match<'a>(
     path: &Path,
     set: &GlobSet,
-    infos: &[GlobInfo],
+    infos: &'a [GlobInfo],
     indices: &mut Vec<usize>,
-) -> bool {
+) -> Option<&'a GlobInfo> {
     let candidate = Candidate::new(path);
-    let log_name = debug_file_name(path);
     set.matches_candidate_into(&candidate, indices);
-    if let Some(&idx) = indices.last() {
-        let glob_str = &infos[idx].str;
-        if infos[idx].inverted {
+    indices.last().map(|&idx| &infos[idx])
+}
+
+/// Returns the glob which prevents inlining the path, if any.
+fn check_should_inline<'a>(
+    path: &Path,
+    set: &GlobSet,
+    infos: &'a [GlobInfo],
+    indices: &mut Vec<usize>,
+) -> Option<&'a str> {
+    let log_name = debug_file_name(path);
+    if let Some(info) = last_match(path, set, infos, indices) {
+        let glob_str = &info.str;
+        if info.inverted {
             debug!("Inlining {:?} (cause: '{}')", log_name, glob_str);
-            true
+            None
         } else {
             debug!("Not inlining {:?} (cause: '{}')", log_name, glob_str);
-            false
+            Some(glob_str)
         }
     } else {
         debug!("Inlining {:?} by default", log_name);
-        true
+        None
     }
 }
 
@@ -106,7 +117,9 @@ impl InliningFilter {
     }
 
     /// Check whether a path should be included.
-    pub fn should_inline(&mut self, path: &Path, is_system: bool) -> bool {
+    ///
+    /// Returns the glob excluding the path from inlining, or `None` if it should be inlined.
+    pub fn excluded_by(&mut self, path: &Path, is_system: bool) -> Option<&str> {
         let (set, infos) = if is_system {
             (&self.system_set, &self.system_infos)
         } else {
@@ -115,3 +128,44 @@ impl InliningFilter {
         check_should_inline(path, set, infos, &mut self.indices)
     }
 }
+
+/// Decides which headers are inlined every time they are included, rather than just once.
+#[derive(Debug)]
+pub struct MultiIncludeFilter {
+    set: GlobSet,
+    infos: Vec<GlobInfo>,
+    indices: Vec<usize>,
+    unguarded: bool,
+}
+
+impl MultiIncludeFilter {
+    /// Creates a new filter from a list of globs.
+    ///
+    /// With `unguarded` set, headers not matched by any glob are inlined again if they contain
+    /// neither `#pragma once` nor a header guard.
+    pub fn new(globs: impl IntoIterator<Item = InvertibleGlob>, unguarded: bool) -> Result<Self> {
+        let (set, infos) = build_set_and_infos("Multi-include", globs)?;
+        Ok(Self {
+            set,
+            infos,
+            indices: Vec::new(),
+            unguarded,
+        })
+    }
+
+    /// Check whether an already inlined header should be inlined again.
+    pub fn is_multi_include(&mut self, path: &Path, include_once: bool) -> bool {
+        let log_name = debug_file_name(path);
+        if let Some(info) = last_match(path, &self.set, &self.infos, &mut self.indices) {
+            debug!(
+                "{:?} is {}a multi-include header (cause: '{}')",
+                log_name,
+                if info.inverted { "not " } else { "" },
+                info.str
+            );
+            !info.inverted
+        } else {
+            self.unguarded && !include_once
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Recording and exporting of the include graph
+use std::{
+    fmt::{self, Display, Formatter},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Error, Result};
+use serde::Serialize;
+
+/// Whether an include uses quotes or angle brackets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IncludeKind {
+    Quote,
+    System,
+    /// A computed include such as `#include CONFIG_HEADER`, which could not be expanded
+    Computed,
+}
+
+/// What was done with an include statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IncludeDecision {
+    /// The header was inlined
+    Inlined,
+    /// The header was already inlined before, so the include was removed
+    Skipped,
+    /// The include was left in place because of a filter glob
+    Filtered,
+    /// The include was left in place because it could not be resolved
+    Unresolved,
+    /// The include was left in place because it would cause a cycle
+    Cyclic,
+    /// The include was left in place because it is in a branch known not to be taken
+    Inactive,
+    /// The include was left in place because it is in a branch whose condition could not be
+    /// decided
+    Undecided,
+    /// The include was left in place because its operand could not be expanded to a header name
+    Unexpanded,
+}
+
+impl Display for IncludeDecision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Inlined => "inlined",
+            Self::Skipped => "skipped",
+            Self::Filtered => "filtered",
+            Self::Unresolved => "unresolved",
+            Self::Cyclic => "cyclic",
+            Self::Inactive => "inactive",
+            Self::Undecided => "undecided",
+            Self::Unexpanded => "unexpanded",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A single include statement, which forms an edge of the include graph.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Include {
+    /// Canonical path of the file containing the include statement
+    pub from: PathBuf,
+    /// Canonical path of the included file, if it was resolved
+    pub to: Option<PathBuf>,
+    /// The included path as written, including the quotes or angle brackets, or the operand of a
+    /// computed include which could not be expanded
+    pub spelling: String,
+    pub kind: IncludeKind,
+    /// Line of the include statement, starting at 1
+    pub line: usize,
+    pub decision: IncludeDecision,
+}
+
+/// Output format for the include graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    Json,
+}
+
+impl GraphFormat {
+    pub const NAMES: [&'static str; 2] = ["dot", "json"];
+}
+
+impl FromStr for GraphFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "dot" => Self::Dot,
+            "json" => Self::Json,
+            _ => bail!("Invalid graph format: \"{}\"", s),
+        })
+    }
+}
+
+struct DotString<'a>(&'a str);
+
+impl Display for DotString<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            if c == '"' || c == '\\' {
+                write!(f, "\\")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        write!(f, "\"")
+    }
+}
+
+fn write_dot(mut writer: impl Write, includes: &[Include]) -> Result<()> {
+    writeln!(writer, "digraph includes {{")?;
+    for include in includes {
+        let from = include.from.to_string_lossy();
+        let to = include.to.as_ref().map_or_else(
+            || include.spelling.clone(),
+            |to| to.to_string_lossy().into(),
+        );
+        if include.to.is_none() {
+            writeln!(writer, "    {} [style=dashed];", DotString(&to))?;
+        }
+
+        let label = format!(
+            "{}:{} ({})",
+            include.spelling, include.line, include.decision
+        );
+        let style = if include.decision == IncludeDecision::Inlined {
+            "solid"
+        } else {
+            "dashed"
+        };
+        writeln!(
+            writer,
+            "    {} -> {} [label={}, style={}];",
+            DotString(&from),
+            DotString(&to),
+            DotString(&label),
+            style
+        )?;
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct JsonGraph<'a> {
+    includes: &'a [Include],
+}
+
+fn write_json(mut writer: impl Write, includes: &[Include]) -> Result<()> {
+    serde_json::to_writer_pretty(&mut writer, &JsonGraph { includes })?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Writes the include graph in the given format.
+pub fn write(mut writer: impl Write, includes: &[Include], format: GraphFormat) -> Result<()> {
+    match format {
+        GraphFormat::Dot => write_dot(&mut writer, includes),
+        GraphFormat::Json => write_json(&mut writer, includes),
+    }
+    .and_then(|()| Ok(writer.flush()?))
+    .context("Failed to write include graph")
+}
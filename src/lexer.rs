@@ -0,0 +1,268 @@
+//! Minimal C++ lexer tracking comments and literals across lines.
+use std::ops::Range;
+
+const RAW_STRING_PREFIXES: [&[u8]; 5] = [b"R", b"u8R", b"uR", b"UR", b"LR"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Code,
+    BlockComment,
+    /// A line comment continued onto the next line by a trailing backslash.
+    LineComment,
+    /// A string or character literal continued onto the next line by a trailing backslash.
+    Literal(u8),
+    /// A raw string literal, storing the sequence which terminates it.
+    RawString(Vec<u8>),
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::Code
+    }
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn is_identifier_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// Whether a line starts with `#` or its digraph `%:`, ignoring leading whitespace.
+pub fn is_directive_start(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    trimmed.starts_with('#') || trimmed.starts_with("%:")
+}
+
+/// Whether a line is continued onto the next one by a trailing backslash.
+pub fn is_continued(line: &str) -> bool {
+    line.trim_end_matches(|c| c == '\n' || c == '\r')
+        .ends_with('\\')
+}
+
+/// Splices continued lines and replaces each comment by a single space.
+///
+/// This is used to normalize preprocessor directives before matching them.
+pub fn normalize_directive(text: &str) -> String {
+    let spliced = text.replace("\\\r\n", "").replace("\\\n", "");
+    let mut normalized = String::with_capacity(spliced.len());
+    let mut rest = spliced.as_str();
+    let mut quote = None;
+    let mut escaped = false;
+    while let Some(c) = rest.chars().next() {
+        if let Some(quote_char) = quote {
+            quote = (escaped || c != quote_char).then(|| quote_char);
+            escaped = c == '\\' && !escaped;
+        } else if rest.starts_with("//") {
+            normalized.push(' ');
+            break;
+        } else if let Some(tail) = rest.strip_prefix("/*") {
+            normalized.push(' ');
+            rest = tail.find("*/").map_or("", |end| &tail[(end + 2)..]);
+            continue;
+        } else if c == '"'
+            || (c == '\'' && !normalized.ends_with(|prev: char| prev.is_ascii_alphanumeric()))
+        {
+            // A ' following an alphanumeric character is a digit separator
+            quote = Some(c);
+        }
+
+        normalized.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    normalized
+}
+
+/// Returns the byte ranges of all comments in a file, including their `//` or `/* */`.
+///
+/// Comments continued onto further lines are returned as a single range.
+pub fn comment_ranges(content: &str) -> Vec<Range<usize>> {
+    let mut lexer = Lexer::default();
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut line_start = 0;
+    for line in content.split_inclusive('\n') {
+        let continues_comment = lexer.in_comment();
+        lexer.scan_line_with(line, |range| {
+            let range = (line_start + range.start)..(line_start + range.end);
+            match ranges.last_mut() {
+                Some(last) if continues_comment && range.start == line_start => {
+                    last.end = range.end;
+                }
+                _ => ranges.push(range),
+            }
+        });
+        line_start += line.len();
+    }
+    ranges
+}
+
+/// Lexer state carried from one line of a file to the next.
+#[derive(Debug, Default)]
+pub struct Lexer {
+    state: State,
+}
+
+impl Lexer {
+    /// Whether the next line starts outside of any comment or literal.
+    ///
+    /// Only such lines can contain preprocessor directives.
+    pub fn in_code(&self) -> bool {
+        self.state == State::Code
+    }
+
+    /// Whether the next line starts inside of a block comment.
+    pub fn in_block_comment(&self) -> bool {
+        self.state == State::BlockComment
+    }
+
+    fn in_comment(&self) -> bool {
+        matches!(self.state, State::BlockComment | State::LineComment)
+    }
+
+    /// Scans a line, updating the state for the following line.
+    ///
+    /// Returns whether the line contains anything besides whitespace and comments.
+    pub fn scan_line(&mut self, line: &str) -> bool {
+        self.scan_line_with(line, |_| {})
+    }
+
+    /// Scans a line like [`scan_line`](Self::scan_line), passing the byte range of each comment in
+    /// it to `on_comment`.
+    ///
+    /// Comments spanning multiple lines are passed as one range for each line, excluding the line
+    /// ending.
+    fn scan_line_with(&mut self, line: &str, mut on_comment: impl FnMut(Range<usize>)) -> bool {
+        let line = line.trim_end_matches(|c| c == '\n' || c == '\r').as_bytes();
+        let mut significant = false;
+        let mut pos = 0;
+        let mut comment_start = 0;
+        while pos < line.len() {
+            let was_comment = self.in_comment();
+            let start = pos;
+            let rest = &line[pos..];
+            match &self.state {
+                State::BlockComment => match find_bytes(rest, b"*/") {
+                    Some(end) => {
+                        pos += end + 2;
+                        self.state = State::Code;
+                    }
+                    None => pos = line.len(),
+                },
+                State::LineComment => pos = line.len(),
+                State::Literal(quote) => {
+                    significant = true;
+                    let quote = *quote;
+                    let mut escaped = false;
+                    let end = rest.iter().position(|&byte| {
+                        let is_end = byte == quote && !escaped;
+                        escaped = byte == b'\\' && !escaped;
+                        is_end
+                    });
+                    match end {
+                        Some(end) => {
+                            pos += end + 1;
+                            self.state = State::Code;
+                        }
+                        None => pos = line.len(),
+                    }
+                }
+                State::RawString(terminator) => {
+                    significant = true;
+                    match find_bytes(rest, terminator) {
+                        Some(end) => {
+                            pos += end + terminator.len();
+                            self.state = State::Code;
+                        }
+                        None => pos = line.len(),
+                    }
+                }
+                State::Code => pos += self.scan_code(rest, &mut significant),
+            }
+
+            match (was_comment, self.in_comment()) {
+                (false, true) => comment_start = start,
+                (true, false) => on_comment(comment_start..pos),
+                _ => {}
+            }
+        }
+        if self.in_comment() {
+            on_comment(comment_start..line.len());
+        }
+
+        let continued = line.ends_with(b"\\");
+        if !continued && matches!(self.state, State::LineComment | State::Literal(_)) {
+            self.state = State::Code;
+        }
+        significant
+    }
+
+    /// Scans a single token in code, returning its length.
+    fn scan_code(&mut self, rest: &[u8], significant: &mut bool) -> usize {
+        let byte = rest[0];
+        if byte.is_ascii_whitespace() {
+            return 1;
+        }
+        if rest.starts_with(b"//") {
+            self.state = State::LineComment;
+            return rest.len();
+        }
+        if rest.starts_with(b"/*") {
+            self.state = State::BlockComment;
+            return 2;
+        }
+
+        *significant = true;
+        if byte == b'"' || byte == b'\'' {
+            self.state = State::Literal(byte);
+            1
+        } else if byte.is_ascii_digit()
+            || (byte == b'.' && rest.get(1).map_or(false, u8::is_ascii_digit))
+        {
+            let mut len = 1;
+            while let Some(&next) = rest.get(len) {
+                let continues = is_identifier_byte(next)
+                    || next == b'.'
+                    || (next == b'\''
+                        && rest.get(len + 1).map_or(false, |&b| is_identifier_byte(b)))
+                    || (matches!(next, b'+' | b'-')
+                        && matches!(rest[len - 1], b'e' | b'E' | b'p' | b'P'));
+                if !continues {
+                    break;
+                }
+                len += 1;
+            }
+            len
+        } else if is_identifier_byte(byte) {
+            let len = rest
+                .iter()
+                .position(|&b| !is_identifier_byte(b))
+                .unwrap_or(rest.len());
+            let is_raw_string =
+                RAW_STRING_PREFIXES.contains(&&rest[..len]) && rest.get(len) == Some(&b'"');
+            if !is_raw_string {
+                return len;
+            }
+
+            let delimiter_start = len + 1;
+            let delimiter_len = rest[delimiter_start..].iter().position(|&b| b == b'(');
+            match delimiter_len {
+                Some(delimiter_len) => {
+                    let delimiter = &rest[delimiter_start..(delimiter_start + delimiter_len)];
+                    let mut terminator = Vec::with_capacity(delimiter_len + 2);
+                    terminator.push(b')');
+                    terminator.extend_from_slice(delimiter);
+                    terminator.push(b'"');
+                    self.state = State::RawString(terminator);
+                    delimiter_start + delimiter_len + 1
+                }
+                None => len,
+            }
+        } else {
+            1
+        }
+    }
+}
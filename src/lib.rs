@@ -0,0 +1,72 @@
+//! Library interface of cpp-amalgamate, which recursively combines C++ source files and the
+//! headers they include into a single output.
+//!
+//! The entry point is the [`Amalgamator`] builder, which offers the same options as the cli.
+#![warn(
+    // Lint groups
+    future_incompatible,
+    nonstandard_style,
+    rust_2018_compatibility,
+    rust_2018_idioms,
+    rust_2021_compatibility,
+    // Allow by default
+    elided_lifetimes_in_paths,
+    missing_debug_implementations,
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    // Clippy
+    clippy::all,
+    clippy::pedantic,
+    clippy::cargo,
+    clippy::clone_on_ref_ptr,
+    clippy::decimal_literal_representation,
+    clippy::filetype_is_file,
+    clippy::float_cmp_const,
+    clippy::get_unwrap,
+    clippy::if_then_some_else_none,
+    clippy::rc_mutex,
+    clippy::rest_pat_in_fully_bound_structs,
+    clippy::shadow_unrelated,
+    clippy::todo,
+    clippy::unimplemented,
+    clippy::unwrap_used,
+    clippy::verbose_file_reads,
+)]
+#![allow(clippy::module_name_repetitions, clippy::non_ascii_literal)]
+
+#[macro_use]
+mod logging;
+
+mod amalgamator;
+mod annotations;
+mod comments;
+mod compile_commands;
+mod compiler;
+mod conditional;
+mod env_paths;
+mod filter;
+mod graph;
+mod lexer;
+mod line_paths;
+mod process;
+mod resolve;
+mod source_map;
+mod split;
+
+pub use crate::{
+    amalgamator::{Amalgamation, Amalgamator},
+    annotations::AnnotationLevel,
+    comments::CommentStripping,
+    conditional::{MacroDefinition, MacroOption},
+    env_paths::Language,
+    filter::InvertibleGlob,
+    graph::{GraphFormat, Include, IncludeDecision, IncludeKind},
+    line_paths::PathPrefixRemap,
+    logging::ErrorHandling,
+    process::LineDirectiveStyle,
+    source_map::{LineRange, SourceMap},
+    split::{split, SplitFile},
+};
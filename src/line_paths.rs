@@ -0,0 +1,128 @@
+//! Formatting of the file names written in line directives
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Error, Result};
+
+/// Replacement of a path prefix in line directives, written as `FROM=TO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPrefixRemap {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+impl PathPrefixRemap {
+    pub fn new(from: impl Into<PathBuf>, to: impl Into<PathBuf>) -> Self {
+        Self {
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+}
+
+impl FromStr for PathPrefixRemap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Just like rustc, split at the last '=' since it is more likely to occur in FROM
+        match s.rsplit_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(Self::new(from, to)),
+            _ => bail!("Invalid path prefix remapping \"{}\", expected FROM=TO", s),
+        }
+    }
+}
+
+/// Turns file paths into the file names written in line directives.
+#[derive(Debug, Clone)]
+pub struct LinePathFormatter {
+    /// Canonical directory which paths are made relative to
+    base: Option<PathBuf>,
+    remaps: Vec<PathPrefixRemap>,
+}
+
+impl LinePathFormatter {
+    pub fn new(base: Option<PathBuf>, remaps: Vec<PathPrefixRemap>) -> Self {
+        Self { base, remaps }
+    }
+
+    /// Returns the file name for the path.
+    ///
+    /// The last matching prefix remapping is applied. Otherwise, absolute paths are made relative
+    /// to the base directory, if one is given.
+    pub fn file_name<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let remapped = self.remaps.iter().rev().find_map(|remap| {
+            let rest = path.strip_prefix(&remap.from).ok()?;
+            // Joining an empty path would add a trailing separator
+            if rest.as_os_str().is_empty() {
+                Some(remap.to.clone())
+            } else {
+                Some(remap.to.join(rest))
+            }
+        });
+        match (remapped, &self.base) {
+            (Some(remapped), _) => remapped.into(),
+            (None, Some(base)) if path.is_absolute() => {
+                relative_path(path, base).map_or(path.into(), Cow::from)
+            }
+            (None, _) => path.into(),
+        }
+    }
+
+    /// Returns the file name for the path as a C string literal, including the quotes.
+    pub fn format(&self, path: &Path) -> String {
+        escape_c_string(&self.file_name(path).to_string_lossy())
+    }
+}
+
+/// Returns the path relative to the base, or `None` if they do not share a root.
+fn relative_path(path: &Path, base: &Path) -> Option<PathBuf> {
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    let mut common = 0;
+    while let (Some(path_component), Some(base_component)) =
+        (path_components.peek(), base_components.peek())
+    {
+        if path_component != base_component {
+            break;
+        }
+        path_components.next();
+        base_components.next();
+        common += 1;
+    }
+
+    // On Windows, paths on different drives have no relative path
+    if common == 0 {
+        return None;
+    }
+    let relative: PathBuf = base_components
+        .map(|_| Component::ParentDir)
+        .chain(path_components)
+        .collect();
+    Some(relative)
+}
+
+/// Quotes a string for a C string literal, escaping all characters which need it.
+fn escape_c_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_ascii_control() => {
+                write!(escaped, "\\{:03o}", u32::from(c)).expect("writing to a String failed");
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
@@ -0,0 +1,94 @@
+//! Mapping locations in an amalgamated file back to the files they were copied from
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use cpp_amalgamate::SourceMap;
+use log::debug;
+use regex::{Captures, Regex};
+
+/// Loads the source map of an amalgamated file.
+///
+/// Without a source map file, the map is recovered from the line directives in the file, or from
+/// its annotations if it has no line directives.
+pub fn load_source_map(file: &Path, source_map_file: Option<&Path>) -> Result<SourceMap> {
+    if let Some(path) = source_map_file {
+        let error_context = || format!("Failed to load source map \"{}\"", path.display());
+        let reader = BufReader::new(File::open(path).with_context(error_context)?);
+        return SourceMap::read(reader).with_context(error_context);
+    }
+
+    let content = fs::read_to_string(file)
+        .with_context(|| format!("Failed to read file \"{}\"", file.display()))?;
+    let mut source_map = SourceMap::from_line_directives(&content);
+    if source_map.ranges.is_empty() {
+        source_map = SourceMap::from_annotations(&content);
+    }
+    if source_map.ranges.is_empty() {
+        bail!(
+            "\"{}\" contains neither line directives nor annotations, use --map to give its \
+             source map",
+            file.display()
+        );
+    }
+    Ok(source_map)
+}
+
+/// Returns the original location of a line of the amalgamated file.
+pub fn locate_line(source_map: &SourceMap, file: &Path, line: usize) -> Result<String> {
+    match source_map.lookup(line) {
+        Some((path, num)) => Ok(format!("{}:{}", path.display(), num)),
+        None => bail!(
+            "Line {} of \"{}\" was not copied from any file",
+            line,
+            file.display()
+        ),
+    }
+}
+
+/// Builds a regex matching `name:line` and `name:line:col` for any of the names.
+///
+/// Names must not be preceded by characters which could be part of a longer path.
+fn location_regex<'a>(names: impl IntoIterator<Item = &'a str>) -> Regex {
+    let names: Vec<_> = names.into_iter().map(regex::escape).collect();
+    let re = format!(r"(^|[^\w./\\-])({}):(\d+)(:\d+)?", names.join("|"));
+    Regex::new(&re).expect("escaped names produced an invalid regex")
+}
+
+/// Copies compiler diagnostics, rewriting all locations in the amalgamated file to the locations
+/// they were copied from.
+///
+/// Locations in lines which were not copied from any file are left as is.
+pub fn rewrite_diagnostics<'a>(
+    reader: impl BufRead,
+    mut writer: impl Write,
+    source_map: &SourceMap,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<()> {
+    let regex = location_regex(names);
+    debug!("Matching locations using {}", regex);
+    for line in reader.lines() {
+        let line = line.context("Failed to read diagnostics")?;
+        let rewritten = regex.replace_all(&line, |captures: &Captures<'_>| {
+            let location = captures[3]
+                .parse()
+                .ok()
+                .and_then(|num| source_map.lookup(num));
+            match location {
+                Some((path, num)) => format!(
+                    "{}{}:{}{}",
+                    &captures[1],
+                    path.display(),
+                    num,
+                    captures.get(4).map_or("", |col| col.as_str())
+                ),
+                None => captures[0].to_owned(),
+            }
+        });
+        writeln!(writer, "{}", rewritten).context("Failed to write diagnostics")?;
+    }
+    writer.flush().context("Failed to write diagnostics")
+}
@@ -1,4 +1,4 @@
-/// Simple stderr logger, with level filter and color controllable by cli arguments.
+//! Logging helpers and configurable handling of recoverable errors.
 use std::{ffi::OsStr, path::Path, str::FromStr};
 
 use anyhow::{Error, Result};
@@ -8,10 +8,14 @@ pub fn debug_file_name(path: &Path) -> &OsStr {
     path.file_name().unwrap_or(default)
 }
 
+/// How to handle a recoverable error, such as an include which cannot be resolved.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorHandling {
+    /// Abort processing with an error
     Error,
+    /// Log a warning and continue
     Warn,
+    /// Continue silently
     Ignore,
 }
 
@@ -32,7 +36,6 @@ impl FromStr for ErrorHandling {
     }
 }
 
-#[macro_export]
 macro_rules! error_handling_handle {
     ($handling:expr, $fmt:expr, $($arg:tt)*) => {{
         match $handling {
@@ -33,54 +33,354 @@
 )]
 #![allow(clippy::module_name_repetitions, clippy::non_ascii_literal)]
 
+// The code derived by clap for subcommands triggers unused_qualifications
+#[allow(unused_qualifications)]
 mod cli;
-mod filter;
-mod logging;
-mod process;
-mod resolve;
+mod config;
+mod locate;
+mod watch;
 
 use std::{
+    collections::BTreeSet,
     env,
-    fs::File,
+    ffi::OsStr,
+    fs::{self, File},
     io::{self, BufWriter, Write},
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use cpp_amalgamate::{Amalgamator, GraphFormat, SplitFile};
 use log::{error, info};
 
 use crate::{
-    cli::Opts,
-    filter::InliningFilter,
-    logging::ErrorHandling,
-    process::{ErrorHandlingOpts, Processor},
-    resolve::IncludeResolver,
+    cli::{Command, Opts},
+    config::{Config, Outputs},
 };
 
-fn run_with_writer(opts: &Opts, writer: impl Write) -> Result<()> {
-    let resolver = IncludeResolver::new(
-        opts.quote_search_dirs().map(PathBuf::from).collect(),
-        opts.system_search_dirs().map(PathBuf::from).collect(),
-    )?;
-    let filter = InliningFilter::new(
-        opts.quote_filter_globs().cloned(),
-        opts.system_filter_globs().cloned(),
-    )?;
-    let error_handling_opts = ErrorHandlingOpts {
-        cyclic_include: opts.cyclic_include_handling(),
-        unresolvable_quote_include: opts.unresolvable_quote_include_handling(),
-        unresolvable_system_include: opts.unresolvable_system_include_handling(),
+/// Adds the search directories given on the cli, which are searched before those from any
+/// configuration file.
+fn apply_cli_search_dirs(opts: &Opts, amalgamator: &mut Amalgamator) {
+    for dir in opts.quote_search_dirs() {
+        amalgamator.quote_search_dir(dir);
+    }
+    for dir in opts.system_search_dirs() {
+        amalgamator.system_search_dir(dir);
+    }
+}
+
+/// Applies the options given on the cli, which take precedence over any configuration file.
+///
+/// The search directories are added separately by [`apply_cli_search_dirs`].
+fn apply_cli_opts(opts: &Opts, amalgamator: &mut Amalgamator) {
+    if let Some(path) = &opts.compile_commands {
+        amalgamator.compile_commands(path);
+    }
+    if let Some(compiler) = &opts.system_dirs_from {
+        amalgamator.system_dirs_from(compiler);
+    }
+    if let Some(language) = opts.language {
+        amalgamator.language(language);
+    }
+    for glob in opts.quote_filter_globs() {
+        amalgamator.quote_filter(glob.clone());
+    }
+    for glob in opts.system_filter_globs() {
+        amalgamator.system_filter(glob.clone());
+    }
+    for glob in opts.multi_include_globs() {
+        amalgamator.multi_include(glob.clone());
+    }
+    for option in opts.macro_options() {
+        amalgamator.macro_option(option.clone());
+    }
+
+    if let Some(handling) = opts.cyclic_include_handling() {
+        amalgamator.cyclic_include(handling);
+    }
+    if let Some(handling) = opts.unresolvable_quote_include_handling() {
+        amalgamator.unresolvable_quote_include(handling);
+    }
+    if let Some(handling) = opts.unresolvable_system_include_handling() {
+        amalgamator.unresolvable_system_include(handling);
+    }
+    if opts.use_env_paths || opts.no_use_env_paths {
+        amalgamator.use_env_paths(opts.use_env_paths);
+    }
+    if opts.multi_include_unguarded || opts.no_multi_include_unguarded {
+        amalgamator.multi_include_unguarded(opts.multi_include_unguarded);
+    }
+    if let Some(style) = opts.line_directives {
+        amalgamator
+            .line_directives(true)
+            .line_directive_style(style);
+    } else if opts.no_line_directives {
+        amalgamator.line_directives(false);
+    }
+    if let Some(dir) = &opts.line_directive_base {
+        amalgamator.line_directive_base(dir);
+    }
+    for remap in &opts.remap_path_prefix {
+        amalgamator.remap_path_prefix(remap.clone());
+    }
+    if let Some(level) = opts.annotate {
+        amalgamator.annotate(level);
+    }
+    if let Some(mode) = opts.strip_comments {
+        amalgamator.strip_comments(mode);
+    }
+    if opts.strip_header_guards || opts.no_strip_header_guards {
+        amalgamator.strip_header_guards(opts.strip_header_guards);
+    }
+}
+
+/// Applies the output options given on the cli, which take precedence over any configuration file.
+fn apply_cli_outputs(opts: &Opts, outputs: &mut Outputs) {
+    if let Some(output) = &opts.output {
+        outputs.output = Some(output.clone());
+    }
+    if let Some(dep_file) = &opts.dep_file {
+        outputs.dep_file = Some(dep_file.clone());
+    }
+    if let Some(dep_target) = &opts.dep_target {
+        outputs.dep_target = Some(dep_target.clone());
+    }
+    if opts.dep_phony || opts.no_dep_phony {
+        outputs.dep_phony = opts.dep_phony;
+    }
+    if let Some(graph) = &opts.graph {
+        outputs.graph = Some(graph.clone());
+    }
+    if let Some(graph_format) = opts.graph_format {
+        outputs.graph_format = Some(graph_format);
+    }
+    if let Some(source_map) = &opts.source_map {
+        outputs.source_map = Some(source_map.clone());
+    }
+}
+
+/// Runs the amalgamation, adding all files it depends on to `watched`.
+fn run(
+    amalgamator: &Amalgamator,
+    outputs: &Outputs,
+    watched: &mut BTreeSet<PathBuf>,
+) -> Result<()> {
+    watched.extend(amalgamator.input_files().map(Path::to_owned));
+    watched.extend(amalgamator.compile_commands_file().map(Path::to_owned));
+
+    let dep_target = match (&outputs.dep_file, &outputs.dep_target, &outputs.output) {
+        (None, _, _) => None,
+        (Some(_), Some(target), _) => Some(target.clone()),
+        (Some(_), None, Some(output)) => Some(output.to_string_lossy().into_owned()),
+        (Some(_), None, None) => bail!("--dep-target is required when writing to the terminal"),
     };
-    let mut processor = Processor::new(
-        writer,
-        resolver,
-        opts.line_directives,
-        filter,
-        error_handling_opts,
+
+    let amalgamation = if let Some(out_file) = &outputs.output {
+        info!("Writing to {:?}", out_file);
+        let writer = BufWriter::new(File::create(out_file).context("Failed to open output file")?);
+        amalgamator.amalgamate(writer)?
+    } else {
+        info!("Writing to terminal");
+        let stdout = io::stdout();
+        amalgamator.amalgamate(stdout.lock())?
+    };
+    watched.extend(amalgamation.dependencies().map(Path::to_owned));
+    // Headers added to a search directory might be found instead of those found before
+    watched.extend(amalgamation.search_dirs.iter().cloned());
+
+    if let (Some(dep_file), Some(dep_target)) = (&outputs.dep_file, dep_target) {
+        info!("Writing dependencies to {:?}", dep_file);
+        let writer =
+            BufWriter::new(File::create(dep_file).context("Failed to open dependency file")?);
+        amalgamation.write_dep_file(writer, &dep_target, outputs.dep_phony)?;
+    }
+
+    if let Some(graph) = &outputs.graph {
+        let format = outputs.graph_format.unwrap_or_else(|| {
+            if graph.extension() == Some(OsStr::new("json")) {
+                GraphFormat::Json
+            } else {
+                GraphFormat::Dot
+            }
+        });
+        info!("Writing include graph to {:?}", graph);
+        let writer = BufWriter::new(File::create(graph).context("Failed to open graph file")?);
+        amalgamation.write_graph(writer, format)?;
+    }
+
+    if let Some(source_map) = &outputs.source_map {
+        info!("Writing source map to {:?}", source_map);
+        let writer =
+            BufWriter::new(File::create(source_map).context("Failed to open source map file")?);
+        amalgamation.source_map.write(writer)?;
+    }
+    Ok(())
+}
+
+fn build(
+    opts: &Opts,
+    config: Option<&Config>,
+    targets: &[String],
+    watched: &mut BTreeSet<PathBuf>,
+) -> Result<()> {
+    let config = match config {
+        Some(config) => config,
+        None => bail!(
+            "Could not find {} in the current directory or any of its parents",
+            config::FILE_NAME
+        ),
+    };
+    ensure!(
+        opts.files.is_empty(),
+        "Source files cannot be given when building targets"
+    );
+
+    let targets: Vec<_> = if targets.is_empty() {
+        config.target_names().collect()
+    } else {
+        targets.iter().map(String::as_str).collect()
+    };
+    ensure!(
+        !targets.is_empty(),
+        "No targets declared in {}",
+        config::FILE_NAME
     );
-    opts.files
+    ensure!(
+        (opts.output.is_none()
+            && opts.dep_file.is_none()
+            && opts.dep_target.is_none()
+            && opts.graph.is_none()
+            && opts.source_map.is_none())
+            || targets.len() == 1,
+        "--output, --dep-file, --dep-target, --graph and --source-map can only be used when \
+         building a single target"
+    );
+
+    for target in targets {
+        info!("Building target '{}'", target);
+        let mut amalgamator = Amalgamator::new();
+        apply_cli_search_dirs(opts, &mut amalgamator);
+        let mut outputs = config.apply_target(target, &mut amalgamator)?;
+        apply_cli_opts(opts, &mut amalgamator);
+        apply_cli_outputs(opts, &mut outputs);
+        run(&amalgamator, &outputs, watched)
+            .with_context(|| format!("Failed to build target '{}'", target))?;
+    }
+    Ok(())
+}
+
+/// Runs the amalgamation once, adding all files it depends on to `watched`.
+fn run_once(opts: &Opts, watched: &mut BTreeSet<PathBuf>) -> Result<()> {
+    let config = Config::discover()?;
+    if let Some(config) = &config {
+        watched.insert(config.path().to_owned());
+    }
+    if let Some(Command::Build { targets }) = &opts.command {
+        return build(opts, config.as_ref(), targets, watched);
+    }
+
+    let mut amalgamator = Amalgamator::new();
+    apply_cli_search_dirs(opts, &mut amalgamator);
+    let mut outputs = match &config {
+        Some(config) => config.apply_defaults(&mut amalgamator)?,
+        None => Outputs::default(),
+    };
+    amalgamator.inputs(&opts.files);
+    apply_cli_opts(opts, &mut amalgamator);
+    apply_cli_outputs(opts, &mut outputs);
+    run(&amalgamator, &outputs, watched)
+}
+
+/// Runs the locate subcommand.
+fn locate(file: &Path, line: Option<usize>, map: Option<&Path>, names: &[String]) -> Result<()> {
+    let source_map = locate::load_source_map(file, map)?;
+    if let Some(line) = line {
+        println!("{}", locate::locate_line(&source_map, file, line)?);
+        return Ok(());
+    }
+
+    let file_name = file.to_string_lossy();
+    let canonical_path = file.canonicalize().with_context(|| {
+        format!(
+            "Failed to canonicalize amalgamated file path \"{}\"",
+            file.display()
+        )
+    })?;
+    let canonical_name = canonical_path.to_string_lossy();
+    let names = [&*file_name, &*canonical_name]
+        .into_iter()
+        .chain(names.iter().map(String::as_str));
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    locate::rewrite_diagnostics(stdin.lock(), stdout.lock(), &source_map, names)
+}
+
+/// Returns the deepest directory containing all of the files.
+fn common_dir<'a>(paths: impl IntoIterator<Item = &'a Path>) -> PathBuf {
+    let mut common: Option<PathBuf> = None;
+    for dir in paths.into_iter().filter_map(Path::parent) {
+        common = Some(match common {
+            None => dir.to_owned(),
+            Some(common) => common
+                .components()
+                .zip(dir.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(component, _)| component)
+                .collect(),
+        });
+    }
+    common.unwrap_or_default()
+}
+
+/// Writes the reconstructed files to the directory, relative to the directory containing them.
+fn write_split_files(split_files: &[SplitFile], out_dir: &Path) -> Result<()> {
+    let base_dir = common_dir(
+        split_files
+            .iter()
+            .map(|split_file| split_file.path.as_path()),
+    );
+    for split_file in split_files {
+        // Never write outside of the output directory
+        let relative: PathBuf = split_file
+            .path
+            .strip_prefix(&base_dir)
+            .unwrap_or(&split_file.path)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let path = out_dir.join(relative);
+        info!("Writing {:?}", path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory \"{}\"", parent.display()))?;
+        }
+        fs::write(&path, &split_file.content)
+            .with_context(|| format!("Failed to write file \"{}\"", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Runs the split subcommand.
+fn split(file: &Path, map: Option<&Path>, out_dir: Option<&Path>) -> Result<()> {
+    let source_map = locate::load_source_map(file, map)?;
+    let amalgamated = fs::read_to_string(file)
+        .with_context(|| format!("Failed to read file \"{}\"", file.display()))?;
+    let split_files = cpp_amalgamate::split(&amalgamated, &source_map)?;
+    if let Some(out_dir) = out_dir {
+        return write_split_files(&split_files, out_dir);
+    }
+
+    let changed: Vec<_> = split_files
         .iter()
-        .try_for_each(|source_file| processor.process(source_file))
+        .filter(|split_file| split_file.is_changed())
+        .collect();
+    info!("{} of {} files changed", changed.len(), split_files.len());
+    let stdout = io::stdout();
+    let mut writer = stdout.lock();
+    for split_file in changed {
+        write!(writer, "{}", split_file.unified_diff()).context("Failed to write diff")?;
+    }
+    Ok(())
 }
 
 fn try_main() -> Result<()> {
@@ -98,15 +398,22 @@ fn try_main() -> Result<()> {
     }
     builder.filter_level(opts.log_level()).init();
 
-    if let Some(out_file) = &opts.output {
-        info!("Writing to {:?}", out_file);
-        let writer = BufWriter::new(File::create(out_file).context("Failed to open output file")?);
-        run_with_writer(&opts, writer)
-    } else {
-        info!("Writing to terminal");
-        let stdout = io::stdout();
-        run_with_writer(&opts, stdout.lock())
+    if let Some(Command::Locate {
+        file,
+        line,
+        map,
+        name,
+    }) = &opts.command
+    {
+        return locate(file, *line, map.as_deref(), name);
+    }
+    if let Some(Command::Split { file, map, out_dir }) = &opts.command {
+        return split(file, map.as_deref(), out_dir.as_deref());
+    }
+    if opts.watch {
+        watch::watch(|watched| run_once(&opts, watched))
     }
+    run_once(&opts, &mut BTreeSet::new())
 }
 
 fn main() {
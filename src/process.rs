@@ -1,20 +1,32 @@
 /// Main recursive processing of source files/includes.
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    borrow::Cow,
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
     error,
     fmt::{self, Debug, Display, Formatter},
-    fs::File,
-    io::{BufRead, BufReader, Write},
+    fs,
+    io::{BufRead, Write},
+    mem,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use anyhow::{Context, Result};
-use log::{debug, info, trace};
+use anyhow::{bail, Context, Error, Result};
+use log::{debug, info, trace, warn};
 use regex::{CaptureLocations, Regex};
 
 use crate::{
-    error_handling_handle, filter::InliningFilter, logging::debug_file_name,
-    resolve::IncludeResolver, ErrorHandling,
+    annotations::{self, AnnotationLevel},
+    comments::{self, CommentStripping},
+    conditional::{self, Liveness, MacroDefinition, MacroOption, Macros},
+    filter::{InliningFilter, MultiIncludeFilter},
+    graph::{Include, IncludeDecision, IncludeKind},
+    lexer::{self, Lexer},
+    line_paths::{LinePathFormatter, PathPrefixRemap},
+    logging::debug_file_name,
+    resolve::{IncludeResolver, Origin, Resolved},
+    source_map::SourceMapBuilder,
+    Amalgamation, ErrorHandling,
 };
 
 fn static_regex(re: &'static str) -> Regex {
@@ -40,12 +52,101 @@ impl Display for CyclicIncludeError {
 
 impl error::Error for CyclicIncludeError {}
 
+/// State of a single `#if`/`#endif` block.
+#[derive(Debug)]
+struct ConditionalBlock {
+    /// Liveness of the enclosing code.
+    parent: Liveness,
+    /// Whether any of the previous branches was taken.
+    taken: Liveness,
+    /// Liveness of the current branch, including the enclosing code.
+    liveness: Liveness,
+    seen_else: bool,
+}
+
+impl ConditionalBlock {
+    fn new(parent: Liveness, condition: Liveness) -> Self {
+        Self {
+            parent,
+            taken: condition,
+            liveness: parent.min(condition),
+            seen_else: false,
+        }
+    }
+
+    /// Whether the condition of a following `#elif` needs to be evaluated.
+    fn needs_condition(&self) -> bool {
+        self.parent != Liveness::Dead && self.taken != Liveness::Live
+    }
+
+    fn enter_branch(&mut self, condition: Liveness) {
+        let branch = match self.taken {
+            Liveness::Live => Liveness::Dead,
+            Liveness::Unknown => condition.min(Liveness::Unknown),
+            Liveness::Dead => condition,
+        };
+        self.taken = self.taken.max(condition);
+        self.liveness = self.parent.min(branch);
+    }
+}
+
 #[derive(Debug)]
 struct FileState {
     canonical_path: PathBuf,
+    /// Where the file was found, which `#include_next` depends on
+    origin: Origin,
+    /// Whether the file was found in a system header directory, or relative to such a file
+    system_header: bool,
     included_by: usize,
     line_num: usize,
+    /// Number of further lines the current line is continued onto
+    continuation_lines: usize,
+    /// File name set by a `#line` directive or linemarker in the file itself
+    presumed_name: Option<String>,
+    /// A line number and the number it is presumed to have, as set by a `#line` directive
+    presumed_line_base: (usize, usize),
     in_stack: bool,
+    conditionals: Vec<ConditionalBlock>,
+    header_guard: Option<HeaderGuard>,
+    /// Whether the file contains `#pragma once` or a header guard.
+    include_once: bool,
+    lexer: Lexer,
+    /// The lines of the file with comments removed, if comments are stripped
+    stripped_lines: Option<Vec<String>>,
+}
+
+impl FileState {
+    fn new(
+        canonical_path: PathBuf,
+        origin: Origin,
+        system_header: bool,
+        included_by: usize,
+    ) -> Self {
+        Self {
+            canonical_path,
+            origin,
+            system_header,
+            included_by,
+            line_num: 0,
+            continuation_lines: 0,
+            presumed_name: None,
+            presumed_line_base: (1, 1),
+            in_stack: true,
+            conditionals: Vec::new(),
+            header_guard: None,
+            include_once: false,
+            lexer: Lexer::default(),
+            stripped_lines: None,
+        }
+    }
+}
+
+impl FileState {
+    /// The line number of the current line, as changed by `#line` directives.
+    fn presumed_line_num(&self) -> usize {
+        let (line_num, presumed_line_num) = self.presumed_line_base;
+        self.line_num - line_num + presumed_line_num
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -54,32 +155,290 @@ struct LineRef {
     num: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_field_names)]
 pub struct ErrorHandlingOpts {
     pub cyclic_include: ErrorHandling,
     pub unresolvable_quote_include: ErrorHandling,
     pub unresolvable_system_include: ErrorHandling,
 }
 
+/// Syntax of the line directives added to the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineDirectiveStyle {
+    /// Standard `#line num "file"` directives
+    Line,
+    /// GCC linemarkers (`# num "file" flags`), which mark entering and leaving included files as
+    /// well as system headers
+    Gcc,
+}
+
+impl LineDirectiveStyle {
+    pub const NAMES: [&'static str; 2] = ["line", "gcc"];
+}
+
+impl Default for LineDirectiveStyle {
+    fn default() -> Self {
+        Self::Line
+    }
+}
+
+impl FromStr for LineDirectiveStyle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "line" => Self::Line,
+            "gcc" => Self::Gcc,
+            _ => bail!("Invalid line directive style: \"{}\"", s),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OutputOpts {
+    pub line_directives: bool,
+    pub line_directive_style: LineDirectiveStyle,
+    /// Canonical directory which the paths in line directives are relative to
+    pub line_directive_base: Option<PathBuf>,
+    pub path_prefix_remaps: Vec<PathPrefixRemap>,
+    pub annotations: AnnotationLevel,
+    pub strip_comments: CommentStripping,
+    pub strip_header_guards: bool,
+}
+
+/// A statement which includes another file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IncludeDirective {
+    Include,
+    IncludeNext,
+    /// Objective-C's `#import`, which includes a file at most once
+    Import,
+    /// A C++20 header unit import, such as `import <vector>;`
+    ImportDeclaration,
+}
+
 #[derive(Debug)]
-struct Regexes {
+pub(crate) struct Regexes {
     include: Regex,
     include_locs: CaptureLocations,
+    import_declaration: Regex,
+    import_declaration_locs: CaptureLocations,
+    line_marker: Regex,
+    line_marker_locs: CaptureLocations,
     pragma_once: Regex,
+    conditional: Regex,
+    conditional_locs: CaptureLocations,
 }
 
 impl Regexes {
-    fn new() -> Self {
-        let include = static_regex(r#"^\s*#\s*include\s*(["<][^>"]+[">])\s*$"#);
+    pub(crate) fn new() -> Self {
+        let include = static_regex(r"^\s*(?:#|%:)\s*(include|include_next|import)\b\s*(.*?)\s*$");
         let include_locs = include.capture_locations();
+        let import_declaration =
+            static_regex(r#"^\s*(?:export\s+)?import\s*(["<][^>"]+[">])\s*;\s*$"#);
+        let import_declaration_locs = import_declaration.capture_locations();
+        // Matches both `#line` directives and the linemarkers output by `gcc -E`
+        let line_marker = static_regex(
+            r#"^\s*(?:#|%:)\s*(?:line\s+)?(\d+)(?:\s+"((?:[^"\\]|\\.)*)")?(?:\s+\d+)*\s*$"#,
+        );
+        let line_marker_locs = line_marker.capture_locations();
+        let conditional = static_regex(
+            r"^\s*(?:#|%:)\s*(if|ifdef|ifndef|elif|elifdef|elifndef|else|endif|define|undef)\b\s*(.*?)\s*$",
+        );
+        let conditional_locs = conditional.capture_locations();
         Self {
             include,
             include_locs,
-            pragma_once: static_regex(r"^\s*#\s*pragma\s+once\s*$"),
+            import_declaration,
+            import_declaration_locs,
+            line_marker,
+            line_marker_locs,
+            pragma_once: static_regex(r"^\s*(?:#|%:)\s*pragma\s+once\s*$"),
+            conditional,
+            conditional_locs,
+        }
+    }
+}
+
+impl Regexes {
+    /// Matches a conditional directive, returning the directive name and its arguments.
+    fn match_conditional<'a>(&mut self, line: &'a str) -> Option<(&'a str, &'a str)> {
+        self.conditional
+            .captures_read(&mut self.conditional_locs, line)?;
+        let locs = &self.conditional_locs;
+        let get_capture = |idx| {
+            let (start, end) = locs
+                .get(idx)
+                .expect("invalid hardcoded regex: missing capture group");
+            &line[start..end]
+        };
+        Some((get_capture(1), get_capture(2)))
+    }
+
+    /// Matches a `#line` directive or linemarker, returning the line number and file name.
+    pub(crate) fn match_line_marker(&mut self, line: &str) -> Option<(usize, Option<String>)> {
+        self.line_marker
+            .captures_read(&mut self.line_marker_locs, line)?;
+        let (num_start, num_end) = self
+            .line_marker_locs
+            .get(1)
+            .expect("invalid hardcoded regex: missing capture group");
+        let num = line[num_start..num_end].parse().ok()?;
+        let name = self
+            .line_marker_locs
+            .get(2)
+            .map(|(start, end)| unescape(&line[start..end]));
+        Some((num, name))
+    }
+
+    /// Matches an include statement, returning its kind and its operand.
+    ///
+    /// The operand is usually a header name, but might also be a macro for computed includes.
+    fn match_include<'a>(&mut self, line: &'a str) -> Option<(IncludeDirective, &'a str)> {
+        let get_capture = |locs: &CaptureLocations, idx| {
+            let (start, end) = locs
+                .get(idx)
+                .expect("invalid hardcoded regex: missing capture group");
+            &line[start..end]
+        };
+        if self
+            .include
+            .captures_read(&mut self.include_locs, line)
+            .is_some()
+        {
+            let directive = match get_capture(&self.include_locs, 1) {
+                "include" => IncludeDirective::Include,
+                "include_next" => IncludeDirective::IncludeNext,
+                _ => IncludeDirective::Import,
+            };
+            Some((directive, get_capture(&self.include_locs, 2)))
+        } else if self
+            .import_declaration
+            .captures_read(&mut self.import_declaration_locs, line)
+            .is_some()
+        {
+            Some((
+                IncludeDirective::ImportDeclaration,
+                get_capture(&self.import_declaration_locs, 1),
+            ))
+        } else {
+            None
         }
     }
 }
 
+/// Removes the backslashes from the escape sequences in a string literal.
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            unescaped.extend(chars.next());
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+/// Line numbers of a classic `#ifndef`/`#define`/`#endif` header guard.
+#[derive(Debug)]
+struct HeaderGuard {
+    macro_name: String,
+    ifndef_line: usize,
+    define_line: usize,
+    endif_line: usize,
+}
+
+impl HeaderGuard {
+    /// Detects a header guard enclosing the whole file.
+    ///
+    /// Both `#ifndef NAME` and `#if !defined(NAME)` are recognized for the opening directive.
+    fn detect(content: &str, regexes: &mut Regexes) -> Option<Self> {
+        // Only lines starting outside of comments and literals are considered as directives.
+        let mut lexer = Lexer::default();
+        let mut lines = content.lines().enumerate().filter_map(|(idx, line)| {
+            let is_code = lexer.in_code();
+            lexer.scan_line(line).then(|| {
+                let directive = if is_code {
+                    lexer::normalize_directive(line)
+                } else {
+                    String::new()
+                };
+                (idx + 1, directive)
+            })
+        });
+
+        let (ifndef_line, ifndef) = lines.next()?;
+        let macro_name = match regexes.match_conditional(&ifndef)? {
+            ("ifndef", args) => conditional::parse_macro_name(args).ok()?,
+            ("if", args) => conditional::parse_negated_defined(args)?,
+            _ => return None,
+        };
+
+        let (define_line, define) = lines.next()?;
+        let definition = match regexes.match_conditional(&define)? {
+            ("define", args) => MacroDefinition::from_directive(args).ok()?,
+            _ => return None,
+        };
+        if definition.name() != macro_name {
+            return None;
+        }
+
+        let mut depth = 1_usize;
+        for (line_num, line) in &mut lines {
+            match regexes.match_conditional(&line) {
+                Some(("if" | "ifdef" | "ifndef", _)) => depth += 1,
+                Some(("elif" | "elifdef" | "elifndef" | "else", _)) if depth == 1 => return None,
+                Some(("endif", _)) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return lines.next().is_none().then(|| Self {
+                            macro_name,
+                            ifndef_line,
+                            define_line,
+                            endif_line: line_num,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    fn contains_line(&self, line_num: usize) -> bool {
+        [self.ifndef_line, self.define_line, self.endif_line].contains(&line_num)
+    }
+}
+
+/// Returns for each line of a file whether it might have been removed when inlining the file.
+///
+/// These are include statements, `#pragma once`, line directives, and the lines of a header guard.
+pub(crate) fn removable_lines(content: &str) -> Vec<bool> {
+    let mut regexes = Regexes::new();
+    let header_guard = HeaderGuard::detect(content, &mut regexes);
+    let mut lexer = Lexer::default();
+    content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let is_code = lexer.in_code();
+            lexer.scan_line(line);
+            let directive = &lexer::normalize_directive(line);
+            let is_removable_directive = regexes.pragma_once.is_match(directive)
+                || regexes.match_include(directive).is_some()
+                || regexes.match_line_marker(directive).is_some();
+            let in_header_guard = header_guard
+                .as_ref()
+                .map_or(false, |guard| guard.contains_line(idx + 1));
+            is_code && (is_removable_directive || in_header_guard)
+        })
+        .collect()
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum IncludeHandling {
     Inline,
@@ -92,49 +451,136 @@ pub struct Processor<W> {
     writer: W,
     resolver: IncludeResolver,
     inlining_filter: InliningFilter,
+    multi_include_filter: MultiIncludeFilter,
     files: Vec<FileState>,
     known_files: HashMap<PathBuf, usize>,
+    header_guards: HashMap<String, PathBuf>,
+    strip_header_guards: bool,
     tail_idx: usize,
+    /// The line the output is currently at, if line directives are added
     expected_line: Option<LineRef>,
+    line_directive_style: LineDirectiveStyle,
+    line_paths: LinePathFormatter,
+    annotations: AnnotationLevel,
+    strip_comments: CommentStripping,
     error_handling_opts: ErrorHandlingOpts,
+    macros: Macros,
+    /// Macros defined by -D/-U, before processing any file
+    cli_macros: Macros,
+    /// Macros defined before processing the current source file, which header units are processed
+    /// with
+    initial_macros: Macros,
+    /// Macro options which only apply to the current source file
+    input_macro_options: Vec<MacroOption>,
+    /// All directories searched for includes so far
+    search_dirs: Vec<PathBuf>,
+    /// Identifiers used in the conditions and computed includes of each header unit being processed
+    header_unit_references: Vec<BTreeSet<String>>,
+    /// Files included using `#import` or an import declaration, which are never inlined again
+    imported_files: HashSet<PathBuf>,
     regexes: Regexes,
+    includes: Vec<Include>,
+    source_map: SourceMapBuilder,
 }
 
 impl<W: Write> Processor<W> {
     pub fn new(
         writer: W,
-        resolver: IncludeResolver,
-        line_directives: bool,
         inlining_filter: InliningFilter,
+        multi_include_filter: MultiIncludeFilter,
+        macros: Macros,
         error_handling_opts: ErrorHandlingOpts,
+        output_opts: &OutputOpts,
     ) -> Self {
-        let expected_line = line_directives.then(|| LineRef {
+        let expected_line = output_opts.line_directives.then(|| LineRef {
             file_idx: EMPTY_STACK_IDX,
             num: 0,
         });
         Self {
             writer,
-            resolver,
+            resolver: IncludeResolver::default(),
             inlining_filter,
+            multi_include_filter,
             files: Vec::new(),
             known_files: HashMap::new(),
+            header_guards: HashMap::new(),
+            strip_header_guards: output_opts.strip_header_guards,
             tail_idx: EMPTY_STACK_IDX,
             expected_line,
+            line_directive_style: output_opts.line_directive_style,
+            line_paths: LinePathFormatter::new(
+                output_opts.line_directive_base.clone(),
+                output_opts.path_prefix_remaps.clone(),
+            ),
+            annotations: output_opts.annotations,
+            strip_comments: output_opts.strip_comments,
             error_handling_opts,
+            cli_macros: macros.clone(),
+            initial_macros: macros.clone(),
+            macros,
+            input_macro_options: Vec::new(),
+            search_dirs: Vec::new(),
+            header_unit_references: Vec::new(),
+            imported_files: HashSet::new(),
             regexes: Regexes::new(),
+            includes: Vec::new(),
+            source_map: SourceMapBuilder::new(),
+        }
+    }
+
+    /// Checks whether the file has a header guard whose macro is known to be defined, in which case
+    /// inlining it again would not add anything.
+    fn is_guard_defined(&self, idx: usize) -> bool {
+        let guard = match &self.files[idx].header_guard {
+            Some(guard) => guard,
+            None => return false,
+        };
+        let defined = self.macros.is_defined(&guard.macro_name) == Liveness::Live;
+        if defined {
+            debug!(
+                "Not inlining {:?} again, its header guard {} is defined",
+                debug_file_name(&self.files[idx].canonical_path),
+                guard.macro_name
+            );
         }
+        defined
     }
 
-    fn push_to_stack(&mut self, canonical_path: PathBuf) -> Result<IncludeHandling> {
+    fn push_to_stack(
+        &mut self,
+        canonical_path: PathBuf,
+        origin: Origin,
+        import: bool,
+    ) -> Result<IncludeHandling> {
+        let inline_again = match self.known_files.get(&canonical_path) {
+            Some(&idx) if !self.files[idx].in_stack => {
+                !import
+                    && !self.imported_files.contains(&canonical_path)
+                    && self
+                        .multi_include_filter
+                        .is_multi_include(&canonical_path, self.files[idx].include_once)
+                    && !self.is_guard_defined(idx)
+            }
+            _ => false,
+        };
+        if import {
+            self.imported_files.insert(canonical_path.clone());
+        }
+        // Just like for GCC, files found next to a system header are system headers as well
+        let system_header = match origin {
+            Origin::IncluderDir => self.files[self.tail_idx].system_header,
+            _ => self.resolver.is_system_header_dir(origin),
+        };
+
         match self.known_files.entry(canonical_path) {
             Entry::Vacant(entry) => {
                 let idx = self.files.len();
-                self.files.push(FileState {
-                    canonical_path: entry.key().clone(),
-                    included_by: self.tail_idx,
-                    line_num: 0,
-                    in_stack: true,
-                });
+                self.files.push(FileState::new(
+                    entry.key().clone(),
+                    origin,
+                    system_header,
+                    self.tail_idx,
+                ));
                 info!("Processing {:?}", debug_file_name(entry.key()));
                 entry.insert(idx);
                 self.tail_idx = idx;
@@ -161,6 +607,18 @@ impl<W: Write> Processor<W> {
                         CyclicIncludeError { cycle }
                     )?;
                     Ok(IncludeHandling::Leave)
+                } else if inline_again {
+                    debug!("Inlining {:?} again", debug_file_name(entry.key()));
+                    let new_idx = self.files.len();
+                    self.files.push(FileState::new(
+                        entry.key().clone(),
+                        origin,
+                        system_header,
+                        self.tail_idx,
+                    ));
+                    *entry.into_mut() = new_idx;
+                    self.tail_idx = new_idx;
+                    Ok(IncludeHandling::Inline)
                 } else {
                     debug!(
                         "Skipping {:?}, already included",
@@ -172,72 +630,446 @@ impl<W: Write> Processor<W> {
         }
     }
 
-    fn output_copied_line(&mut self, line: &str) -> Result<()> {
+    /// Writes a line directive stating that the output continues at the given line of the
+    /// current file.
+    ///
+    /// The flag is only used for GCC linemarkers, with 1 for entering a file and 2 for returning
+    /// to it.
+    fn write_line_directive(&mut self, num: usize, flag: Option<u8>) -> Result<()> {
+        let file = &self.files[self.tail_idx];
+        let path = file
+            .presumed_name
+            .as_ref()
+            .map_or(file.canonical_path.as_path(), Path::new);
+        let name = self.line_paths.format(path);
+
+        let mut directive = match self.line_directive_style {
+            LineDirectiveStyle::Line => format!("#line {} {}", num, name),
+            LineDirectiveStyle::Gcc => format!("# {} {}", num, name),
+        };
+        if self.line_directive_style == LineDirectiveStyle::Gcc {
+            if let Some(flag) = flag {
+                directive.push(' ');
+                directive.push(char::from(b'0' + flag));
+            }
+            if file.system_header {
+                directive.push_str(" 3");
+            }
+        }
+        directive.push('\n');
+
+        self.writer.write_all(directive.as_bytes())?;
+        self.source_map.added(&directive);
+        self.expected_line = Some(LineRef {
+            file_idx: self.tail_idx,
+            num,
+        });
+        Ok(())
+    }
+
+    /// Writes a comment on a line of its own, if annotations are enabled.
+    fn write_annotation(&mut self, comment: &str) -> Result<()> {
+        if self.annotations == AnnotationLevel::None {
+            return Ok(());
+        }
+
+        let mut annotation = String::new();
+        if !self.source_map.at_line_start() {
+            annotation.push('\n');
+        }
+        annotation.push_str("// ");
+        annotation.push_str(comment);
+        annotation.push('\n');
+        self.writer
+            .write_all(annotation.as_bytes())
+            .context("Failed writing to output")?;
+        self.source_map.added(&annotation);
         if let Some(expected_line) = &mut self.expected_line {
-            let cur_file = &self.files[self.tail_idx];
+            expected_line.num += annotation.lines().count();
+        }
+        Ok(())
+    }
+
+    /// Returns the line with comments removed if they are stripped, or `None` if the line should be
+    /// left out since it only contained comments.
+    fn stripped_line<'a>(&self, line: &'a str) -> Option<Cow<'a, str>> {
+        let file = &self.files[self.tail_idx];
+        let stripped_lines = if let Some(stripped_lines) = &file.stripped_lines {
+            stripped_lines
+        } else {
+            return Some(line.into());
+        };
+
+        // Continued directives span multiple lines
+        let first = file.line_num - 1;
+        let lines = first..(first + line.split_inclusive('\n').count());
+        let stripped = if let Some(stripped) = stripped_lines.get(lines) {
+            stripped.concat()
+        } else {
+            return Some(line.into());
+        };
+        (!comments::is_comment_only(line, &stripped)).then(|| stripped.into())
+    }
+
+    fn output_copied_line(&mut self, line: &str) -> Result<()> {
+        // Left out lines are accounted for by the line directives below
+        let line = if let Some(line) = self.stripped_line(line) {
+            line
+        } else {
+            return Ok(());
+        };
+
+        if let Some(expected_line) = self.expected_line {
+            let num = self.files[self.tail_idx].presumed_line_num();
             let cur_line = LineRef {
                 file_idx: self.tail_idx,
-                num: cur_file.line_num,
+                num,
             };
-            if cur_line != *expected_line {
-                writeln!(
-                    self.writer,
-                    "#line {} \"{}\"",
-                    cur_line.num,
-                    cur_file.canonical_path.display()
-                )?;
-                *expected_line = cur_line;
+            if cur_line != expected_line {
+                self.write_line_directive(num, None)?;
             }
-            expected_line.num += 1;
         }
+        if let Some(expected_line) = &mut self.expected_line {
+            // Continued directives span multiple lines
+            expected_line.num += line.lines().count().max(1);
+        }
+
+        let file = &self.files[self.tail_idx];
+        // Lines ending inside of a comment, literal or continued line cannot be annotated
+        let annotate_line = self.annotations == AnnotationLevel::Lines
+            && file.lexer.in_code()
+            && !lexer::is_continued(&line)
+            && !line.trim().is_empty();
+        let line = if annotate_line {
+            let num = file.line_num + line.lines().count().max(1) - 1;
+            let file_name = self.line_paths.file_name(&file.canonical_path);
+            annotations::with_line_origin(&line, &file_name, num).into()
+        } else {
+            line
+        };
 
         write!(self.writer, "{}", line)?;
+        self.source_map
+            .copied(&file.canonical_path, file.line_num, &line);
         Ok(())
     }
 
-    /// Returns `true` if the include statement should be kept, `false` if it shouldn't.
-    fn process_include(&mut self, include_ref: &str, current_dir: &Path) -> Result<bool> {
-        assert!(
-            include_ref.len() >= 3,
-            "error in hardcoded include regex: include ref too short"
-        );
+    fn record_include(
+        &mut self,
+        include_ref: &str,
+        resolved_path: Option<PathBuf>,
+        decision: IncludeDecision,
+    ) {
+        let file = &self.files[self.tail_idx];
+        let kind = if !conditional::is_header_name(include_ref) {
+            IncludeKind::Computed
+        } else if include_ref.starts_with('<') {
+            IncludeKind::System
+        } else {
+            IncludeKind::Quote
+        };
+        self.includes.push(Include {
+            from: file.canonical_path.clone(),
+            to: resolved_path,
+            spelling: include_ref.to_owned(),
+            kind,
+            line: file.line_num,
+            decision,
+        });
+    }
 
-        let maybe_resolved_path = if include_ref.starts_with('"') && include_ref.ends_with('"') {
-            self.resolver
-                .resolve_quote(&include_ref[1..(include_ref.len() - 1)], current_dir)?
-        } else if include_ref.starts_with('<') && include_ref.ends_with('>') {
-            self.resolver
-                .resolve_system(&include_ref[1..(include_ref.len() - 1)])?
+    /// Resolves an include, with `next` selecting the semantics of `#include_next`.
+    ///
+    /// The include ref has to be enclosed in either quotes or angle brackets.
+    fn resolve(
+        &self,
+        include_ref: &str,
+        next: bool,
+        current_dir: &Path,
+    ) -> Result<Option<Resolved>> {
+        let path = &include_ref[1..(include_ref.len() - 1)];
+        let is_system = include_ref.starts_with('<');
+        let file = &self.files[self.tail_idx];
+        if next && file.included_by == EMPTY_STACK_IDX {
+            debug!(
+                "#include_next in source file ({}), treating it as #include",
+                self.location()
+            );
+        } else if next {
+            return self
+                .resolver
+                .resolve_next(path, is_system, file.origin, current_dir);
+        }
+
+        if is_system {
+            self.resolver.resolve_system(path)
         } else {
-            debug!("Found weird include-like statement: {}", include_ref);
+            self.resolver.resolve_quote(path, current_dir)
+        }
+    }
+
+    /// Returns `true` if the include statement should be kept, `false` if it shouldn't.
+    ///
+    /// The statement is given as `line`, which is used for annotations.
+    fn process_include(
+        &mut self,
+        line: &str,
+        operand: &str,
+        directive: IncludeDirective,
+        current_dir: &Path,
+    ) -> Result<bool> {
+        let line = line.trim();
+        let expanded;
+        if !conditional::is_header_name(operand) {
+            self.record_header_unit_references(operand);
+        }
+        let include_ref = if conditional::is_header_name(operand) {
+            operand
+        } else if let Some(header_name) = self.macros.expand_header_name(operand) {
+            debug!("Expanded computed include {} to {}", operand, header_name);
+            expanded = header_name;
+            &expanded
+        } else {
+            debug!("Found weird include-like statement: {}", operand);
+            self.record_include(operand, None, IncludeDecision::Unexpanded);
+            self.write_annotation(&annotations::kept(line, "not a header name"))?;
             return Ok(true);
         };
         let is_system = include_ref.starts_with('<');
 
-        if let Some(resolved_path) = maybe_resolved_path {
-            if self
-                .inlining_filter
-                .should_inline(&resolved_path, is_system)
-            {
-                return Ok(match self.push_to_stack(resolved_path)? {
-                    IncludeHandling::Inline => {
-                        self.process_recursively()?;
-                        false
-                    }
-                    IncludeHandling::Remove => false,
-                    IncludeHandling::Leave => true,
-                });
-            }
+        let Resolved {
+            path: resolved_path,
+            origin,
+        } = if let Some(resolved) = self.resolve(
+            include_ref,
+            directive == IncludeDirective::IncludeNext,
+            current_dir,
+        )? {
+            resolved
         } else {
+            self.record_include(include_ref, None, IncludeDecision::Unresolved);
             let handling = if is_system {
                 self.error_handling_opts.unresolvable_system_include
             } else {
                 self.error_handling_opts.unresolvable_quote_include
             };
             error_handling_handle!(handling, "Could not resolve {}", include_ref)?;
+            self.write_annotation(&annotations::kept(line, "not found"))?;
+            return Ok(true);
+        };
+
+        if let Some(glob) = self.inlining_filter.excluded_by(&resolved_path, is_system) {
+            let annotation = annotations::kept(line, &format!("filtered by '{}'", glob));
+            self.record_include(include_ref, Some(resolved_path), IncludeDecision::Filtered);
+            self.write_annotation(&annotation)?;
+            return Ok(true);
         }
 
-        Ok(true)
+        // Recorded before inlining, so that includes are listed in the order they are encountered
+        let include_idx = self.includes.len();
+        self.record_include(
+            include_ref,
+            Some(resolved_path.clone()),
+            IncludeDecision::Inlined,
+        );
+        let import = matches!(
+            directive,
+            IncludeDirective::Import | IncludeDirective::ImportDeclaration
+        );
+        let (decision, keep) = match self.push_to_stack(resolved_path, origin, import)? {
+            IncludeHandling::Inline if directive == IncludeDirective::ImportDeclaration => {
+                // Header units are unaffected by the macros defined before their import, but the
+                // macros they define are visible afterwards
+                let outer_macros = mem::replace(&mut self.macros, self.initial_macros.clone());
+                self.header_unit_references.push(BTreeSet::new());
+                self.process_recursively(include_ref)?;
+                let unit_macros = mem::replace(&mut self.macros, outer_macros);
+                self.check_header_unit_references(include_ref);
+                self.macros.extend(unit_macros);
+                (IncludeDecision::Inlined, false)
+            }
+            IncludeHandling::Inline => {
+                self.process_recursively(include_ref)?;
+                (IncludeDecision::Inlined, false)
+            }
+            IncludeHandling::Remove => {
+                self.write_annotation(&annotations::skipped(line))?;
+                (IncludeDecision::Skipped, false)
+            }
+            IncludeHandling::Leave => {
+                self.write_annotation(&annotations::kept(line, "cyclic include"))?;
+                (IncludeDecision::Cyclic, true)
+            }
+        };
+        self.includes[include_idx].decision = decision;
+        Ok(keep)
+    }
+
+    /// Records the identifiers used in a condition or computed include, if in a header unit.
+    fn record_header_unit_references(&mut self, s: &str) {
+        if let Some(references) = self.header_unit_references.last_mut() {
+            references.extend(conditional::identifiers(s));
+        }
+    }
+
+    /// Warns if the header unit which was just processed uses macros defined before its import,
+    /// which must be the current macros.
+    ///
+    /// The header unit was processed without these, but its inlined contents are compiled with
+    /// them in effect, so the compiler might take different branches than cpp-amalgamate did.
+    fn check_header_unit_references(&mut self, include_ref: &str) {
+        let references = self.header_unit_references.pop().unwrap_or_default();
+        let outer_macros: Vec<_> = references
+            .iter()
+            .filter(|name| !self.macros.agrees_on(&self.initial_macros, name))
+            .map(String::as_str)
+            .collect();
+        if !outer_macros.is_empty() {
+            warn!(
+                "Header unit {} ({}) uses macros defined before its import, which are in effect in \
+                 its inlined contents: {}",
+                include_ref,
+                self.location(),
+                outer_macros.join(", ")
+            );
+        }
+        // The inlined contents are part of the enclosing header unit as well
+        if let Some(enclosing) = self.header_unit_references.last_mut() {
+            enclosing.extend(references);
+        }
+    }
+
+    fn location(&self) -> String {
+        let file = &self.files[self.tail_idx];
+        format!("{}:{}", file.canonical_path.display(), file.line_num)
+    }
+
+    fn current_liveness(&self) -> Liveness {
+        self.files[self.tail_idx]
+            .conditionals
+            .last()
+            .map_or(Liveness::Live, |block| block.liveness)
+    }
+
+    /// Evaluates `__has_include` using the same search directories as for include statements.
+    fn has_include(&self, header_name: &str, next: bool) -> Result<Liveness> {
+        let current_dir = self.files[self.tail_idx]
+            .canonical_path
+            .parent()
+            .context("Processed file has no parent directory")?;
+        let resolved = self.resolve(header_name, next, current_dir)?;
+        Ok(Liveness::from(Some(resolved.is_some())))
+    }
+
+    fn evaluate_condition(&self, directive: &str, args: &str) -> Liveness {
+        let result = match directive {
+            "ifdef" | "elifdef" => {
+                conditional::parse_macro_name(args).map(|name| self.macros.is_defined(&name))
+            }
+            "ifndef" | "elifndef" => {
+                conditional::parse_macro_name(args).map(|name| !self.macros.is_defined(&name))
+            }
+            _ => self.macros.evaluate(args, |header_name, next| {
+                self.has_include(header_name, next)
+            }),
+        };
+
+        match result {
+            Ok(Liveness::Unknown) => {
+                debug!(
+                    "Cannot decide #{} {} ({}), keeping branch as is",
+                    directive,
+                    args,
+                    self.location()
+                );
+                Liveness::Unknown
+            }
+            Ok(liveness) => liveness,
+            Err(error) => {
+                warn!(
+                    "Failed to evaluate #{} {} ({}): {:#}",
+                    directive,
+                    args,
+                    self.location(),
+                    error
+                );
+                Liveness::Unknown
+            }
+        }
+    }
+
+    fn process_conditional(&mut self, directive: &str, args: &str) {
+        if matches!(
+            directive,
+            "if" | "ifdef" | "ifndef" | "elif" | "elifdef" | "elifndef"
+        ) {
+            self.record_header_unit_references(args);
+        }
+        let liveness = self.current_liveness();
+        match directive {
+            "if" | "ifdef" | "ifndef" => {
+                let condition = if liveness == Liveness::Dead {
+                    Liveness::Dead
+                } else {
+                    self.evaluate_condition(directive, args)
+                };
+                self.files[self.tail_idx]
+                    .conditionals
+                    .push(ConditionalBlock::new(liveness, condition));
+            }
+            "elif" | "elifdef" | "elifndef" | "else" => {
+                let needs_condition = match self.files[self.tail_idx].conditionals.last() {
+                    Some(block) if !block.seen_else => block.needs_condition(),
+                    _ => {
+                        warn!("Ignoring unexpected #{} ({})", directive, self.location());
+                        return;
+                    }
+                };
+                let condition = if directive == "else" {
+                    Liveness::Live
+                } else if needs_condition {
+                    self.evaluate_condition(directive, args)
+                } else {
+                    Liveness::Dead
+                };
+
+                let block = self.files[self.tail_idx]
+                    .conditionals
+                    .last_mut()
+                    .expect("conditional stack should not be empty");
+                block.seen_else = directive == "else";
+                block.enter_branch(condition);
+            }
+            "endif" => {
+                if self.files[self.tail_idx].conditionals.pop().is_none() {
+                    warn!("Ignoring unexpected #endif ({})", self.location());
+                }
+            }
+            "define" | "undef" if liveness == Liveness::Dead => {}
+            "define" => match MacroDefinition::from_directive(args) {
+                Ok(definition) if liveness == Liveness::Live => self.macros.define(definition),
+                Ok(definition) => self.macros.forget(definition.name()),
+                Err(error) => warn!("Ignoring #define ({}): {:#}", self.location(), error),
+            },
+            _ => match conditional::parse_macro_name(args) {
+                Ok(name) if liveness == Liveness::Live => self.macros.undefine(&name),
+                Ok(name) => self.macros.forget(&name),
+                Err(error) => warn!("Ignoring #undef ({}): {:#}", self.location(), error),
+            },
+        }
+    }
+
+    /// Appends the next line of the current file to `line`, passing it to the file's lexer.
+    ///
+    /// Returns `false` if at eof.
+    fn read_line(&mut self, reader: &mut impl BufRead, line: &mut String) -> Result<bool> {
+        let file = &mut self.files[self.tail_idx];
+        let prev_len = line.len();
+        let bytes_read = reader.read_line(line).with_context(|| {
+            format!("Failed to read from \"{}\"", file.canonical_path.display())
+        })?;
+        file.lexer.scan_line(&line[prev_len..]);
+        Ok(bytes_read > 0)
     }
 
     /// Returns `true` when a line was processed, `false` if at eof.
@@ -248,65 +1080,254 @@ impl<W: Write> Processor<W> {
         current_dir: &Path,
     ) -> Result<bool> {
         line.clear();
-        let bytes_read = reader.read_line(line).with_context(|| {
-            format!(
-                "Failed to read from \"{}\"",
-                self.files[self.tail_idx].canonical_path.display()
-            )
-        })?;
-
-        if bytes_read == 0 {
+        let is_code = self.files[self.tail_idx].lexer.in_code();
+        if !self.read_line(&mut reader, line)? {
             return Ok(false);
         }
-
         self.files[self.tail_idx].line_num += 1;
-        if self.regexes.pragma_once.is_match(line) {
+
+        // Directives can be continued onto further lines, either by a trailing backslash or a block
+        // comment, which are processed along with them
+        let mut continuation_lines = 0;
+        if is_code && lexer::is_directive_start(line) {
+            while (lexer::is_continued(line) || self.files[self.tail_idx].lexer.in_block_comment())
+                && self.read_line(&mut reader, line)?
+            {
+                continuation_lines += 1;
+            }
+        }
+
+        self.files[self.tail_idx].continuation_lines = continuation_lines;
+        if !is_code || self.process_directive(line, current_dir)? {
+            self.output_copied_line(line)
+                .context("Failed writing to output")?;
+        }
+        let tail = &mut self.files[self.tail_idx];
+        tail.line_num += continuation_lines;
+        tail.continuation_lines = 0;
+        Ok(true)
+    }
+
+    /// Handles a line which might be a preprocessor directive.
+    ///
+    /// Returns `true` if the line should be copied to the output, `false` if it shouldn't.
+    fn process_directive(&mut self, line: &str, current_dir: &Path) -> Result<bool> {
+        let line = &lexer::normalize_directive(line);
+        let liveness = self.current_liveness();
+        if liveness == Liveness::Live && self.regexes.pragma_once.is_match(line) {
             trace!("Skipping pragma once");
-            return Ok(true);
+            self.files[self.tail_idx].include_once = true;
+            self.write_annotation(&annotations::removed(line.trim()))?;
+            return Ok(false);
         }
 
-        let maybe_match = self
-            .regexes
-            .include
-            .captures_read(&mut self.regexes.include_locs, line);
-        if maybe_match.is_some() {
-            let (ref_start, ref_end) = self
-                .regexes
-                .include_locs
-                .get(1)
-                .expect("invalid hardcoded regex: missing capture group");
-            if !self.process_include(&line[ref_start..ref_end], current_dir)? {
-                return Ok(true);
+        let strip_guard = self.strip_header_guards
+            && self.files[self.tail_idx]
+                .header_guard
+                .as_ref()
+                .map_or(false, |guard| {
+                    guard.contains_line(self.files[self.tail_idx].line_num)
+                });
+        if strip_guard {
+            trace!("Skipping header guard");
+            // The guard macro is still defined, so that the file is not inlined again
+            if let Some(("define", args)) = self.regexes.match_conditional(line) {
+                self.process_conditional("define", args);
             }
+            self.write_annotation(&annotations::removed(line.trim()))?;
+            return Ok(false);
+        }
+
+        if let Some((directive, args)) = self.regexes.match_conditional(line) {
+            self.process_conditional(directive, args);
+        } else if let Some((num, name)) = self.regexes.match_line_marker(line) {
+            if liveness == Liveness::Live {
+                let keep = self.process_line_marker(num, name);
+                if !keep {
+                    self.write_annotation(&annotations::removed(line.trim()))?;
+                }
+                return Ok(keep);
+            }
+        } else if let Some((directive, include_ref)) = self.regexes.match_include(line) {
+            let (decision, reason) = match liveness {
+                Liveness::Live => {
+                    return self.process_include(line, include_ref, directive, current_dir)
+                }
+                Liveness::Unknown => (IncludeDecision::Undecided, "in an undecided branch"),
+                Liveness::Dead => (IncludeDecision::Inactive, "in a branch not taken"),
+            };
+            debug!("Not inlining {} {}", include_ref, reason);
+            self.record_include(include_ref, None, decision);
+            self.write_annotation(&annotations::kept(line.trim(), reason))?;
         }
 
-        self.output_copied_line(line)
-            .context("Failed writing to output")?;
         Ok(true)
     }
 
-    fn process_recursively(&mut self) -> Result<()> {
+    /// Updates the presumed location of the following lines.
+    ///
+    /// Returns `true` if the line should be copied to the output, which is only the case if no
+    /// `#line` directives are added to it.
+    fn process_line_marker(&mut self, num: usize, name: Option<String>) -> bool {
+        let file = &mut self.files[self.tail_idx];
+        trace!("Next line is presumed to be line {} of {:?}", num, name);
+        file.presumed_line_base = (file.line_num + 1, num);
+        if name.is_some() {
+            file.presumed_name = name;
+        }
+
+        match &mut self.expected_line {
+            Some(expected_line) => {
+                // Forces writing a #line directive, as the file name might have changed
+                expected_line.file_idx = EMPTY_STACK_IDX;
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn check_header_guard(&mut self, content: &str) {
+        let file = &mut self.files[self.tail_idx];
+        file.header_guard = HeaderGuard::detect(content, &mut self.regexes);
+        file.include_once = file.header_guard.is_some();
+        let guard = match &file.header_guard {
+            Some(guard) => guard,
+            None => return,
+        };
+
+        debug!(
+            "Detected header guard {} in {:?}",
+            guard.macro_name,
+            debug_file_name(&file.canonical_path)
+        );
+        // Guard macros are never predefined, so the guarded contents are taken unless the macro is
+        // known to be defined
+        self.macros.assume_undefined(&guard.macro_name);
+        match self.header_guards.entry(guard.macro_name.clone()) {
+            Entry::Vacant(entry) => {
+                entry.insert(file.canonical_path.clone());
+            }
+            Entry::Occupied(entry) if *entry.get() != file.canonical_path => warn!(
+                "Header guard {} is used by both \"{}\" and \"{}\"",
+                guard.macro_name,
+                entry.get().display(),
+                file.canonical_path.display()
+            ),
+            Entry::Occupied(_) => {}
+        }
+    }
+
+    /// Processes the file at the top of the stack, which was included using the given name.
+    fn process_recursively(&mut self, name: &str) -> Result<()> {
         let path = &self.files[self.tail_idx].canonical_path;
         let current_dir = path
             .parent()
             .context("Processed file has no parent directory")?
             .to_path_buf();
 
-        let mut reader = File::open(path)
-            .with_context(|| format!("Failed to open file \"{}\"", path.display()))
-            .map(BufReader::new)?;
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read file \"{}\"", path.display()))?;
+        self.check_header_guard(&content);
+        if self.strip_comments != CommentStripping::None {
+            self.files[self.tail_idx].stripped_lines =
+                Some(comments::strip_comments(&content, self.strip_comments));
+        }
+
+        if self.annotations != AnnotationLevel::None {
+            let file_name = self
+                .line_paths
+                .file_name(&self.files[self.tail_idx].canonical_path);
+            let annotation = annotations::begin(name, &file_name);
+            self.write_annotation(&annotation)?;
+        }
+
+        // Linemarkers for entering and leaving files are written even if no lines are copied in
+        // between, so that tools can reconstruct the include stack from them
+        let linemarkers =
+            self.expected_line.is_some() && self.line_directive_style == LineDirectiveStyle::Gcc;
+        if linemarkers {
+            let is_source_file = self.files[self.tail_idx].included_by == EMPTY_STACK_IDX;
+            self.write_line_directive(1, (!is_source_file).then(|| 1))
+                .context("Failed writing to output")?;
+        }
+
+        let mut reader = content.as_bytes();
         let mut line = String::new();
 
         while self.process_line(&mut reader, &mut line, &current_dir)? {}
+        let file = &self.files[self.tail_idx];
+        if !file.conditionals.is_empty() {
+            warn!("Unterminated #if in \"{}\"", file.canonical_path.display());
+        }
+        self.write_annotation(&annotations::end(name))?;
 
         self.files[self.tail_idx].in_stack = false;
         self.tail_idx = self.files[self.tail_idx].included_by;
+        if linemarkers && self.tail_idx != EMPTY_STACK_IDX {
+            // Continues after the include statement, which might span multiple lines
+            let includer = &self.files[self.tail_idx];
+            let num = includer.presumed_line_num() + includer.continuation_lines + 1;
+            self.write_line_directive(num, Some(2))
+                .context("Failed writing to output")?;
+        }
 
         Ok(())
     }
 
-    pub fn process(&mut self, source_file: &Path) -> Result<()> {
+    /// Flushes the output and returns a summary of all processed files.
+    pub fn finish(mut self) -> Result<Amalgamation> {
+        self.writer.flush().context("Failed writing to output")?;
+
+        let mut seen = HashSet::new();
+        let mut amalgamation = Amalgamation {
+            search_dirs: self.search_dirs,
+            includes: self.includes,
+            source_map: self.source_map.finish(),
+            ..Amalgamation::default()
+        };
+        for file in &self.files {
+            if seen.insert(&file.canonical_path) {
+                let list = if file.included_by == EMPTY_STACK_IDX {
+                    &mut amalgamation.source_files
+                } else {
+                    &mut amalgamation.inlined_files
+                };
+                list.push(file.canonical_path.clone());
+            }
+        }
+        Ok(amalgamation)
+    }
+
+    /// Processes a source file, resolving its includes using `resolver`.
+    ///
+    /// The macro options are only applied while processing this source file, on top of the macros
+    /// defined so far.
+    pub fn process(
+        &mut self,
+        source_file: &Path,
+        resolver: IncludeResolver,
+        macro_options: Vec<MacroOption>,
+    ) -> Result<()> {
         info!("Processing source file {:?}", debug_file_name(source_file));
+        for dir in resolver.search_dirs() {
+            if !self.search_dirs.iter().any(|known| known == dir) {
+                self.search_dirs.push(dir.to_owned());
+            }
+        }
+        self.resolver = resolver;
+
+        // The state of macros set by the options of the previous source file is unknown, as the
+        // file might have changed them
+        for option in self.input_macro_options.drain(..) {
+            self.macros.forget(option.name());
+        }
+        self.initial_macros = self.cli_macros.clone();
+        for option in &macro_options {
+            self.macros.apply(option.clone());
+            self.initial_macros.apply(option.clone());
+        }
+        self.input_macro_options = macro_options;
+
         let canonical_path = source_file.canonicalize().with_context(|| {
             format!(
                 "Failed to canonicalize source file path \"{}\"",
@@ -315,8 +1336,9 @@ impl<W: Write> Processor<W> {
         })?;
 
         assert_eq!(self.tail_idx, EMPTY_STACK_IDX);
-        if self.push_to_stack(canonical_path)? == IncludeHandling::Inline {
-            self.process_recursively()?;
+        if self.push_to_stack(canonical_path, Origin::Elsewhere, false)? == IncludeHandling::Inline
+        {
+            self.process_recursively(&source_file.display().to_string())?;
         }
         assert_eq!(self.tail_idx, EMPTY_STACK_IDX);
 
@@ -20,47 +20,92 @@ impl Display for IncludePrinter<'_> {
     }
 }
 
+/// Where an included file was found, which determines where `#include_next` continues searching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// A source file, or a file included using an absolute path
+    Elsewhere,
+    /// The directory of the including file, for quote includes
+    IncluderDir,
+    /// The search directory with the given index in the quote or system search path
+    SearchDir { system: bool, idx: usize },
+}
+
 #[derive(Debug)]
+pub struct Resolved {
+    /// Canonical path to the file
+    pub path: PathBuf,
+    pub origin: Origin,
+}
+
+#[derive(Debug, Default)]
 pub struct IncludeResolver {
     quote_search_paths: Vec<PathBuf>,
     system_search_paths: Vec<PathBuf>,
+    /// Directories whose headers are treated as system headers, like those given by `-isystem`
+    system_header_dirs: Vec<PathBuf>,
 }
 
 fn resolve(
     path: &str,
     search_path: &[PathBuf],
+    system: bool,
     current_dir: Option<&Path>,
-) -> Result<Option<PathBuf>> {
-    let printer = IncludePrinter(path, current_dir.is_some());
-    let current_dir_canonicalized = current_dir
-        .map(Path::canonicalize)
-        .transpose()
-        .context("failed to canonicalize current directory")?;
-
-    let maybe_resolved = current_dir_canonicalized
-        .as_deref()
-        .into_iter()
-        .chain(search_path.iter().map(PathBuf::as_path))
-        .find_map(|include_dir| {
-            let potential_path = include_dir.join(path);
-            trace!("Trying to resolve {} to {:?}", printer, potential_path);
-
-            (potential_path.exists() && !potential_path.is_dir()).then(|| {
-                potential_path.canonicalize().with_context(|| {
-                    format!(
-                        "Failed to canonicalize path to include: \"{}\"",
-                        potential_path.display()
-                    )
+    start_idx: usize,
+) -> Result<Option<Resolved>> {
+    let printer = IncludePrinter(path, !system);
+    let canonicalize = |potential_path: &Path| {
+        potential_path.canonicalize().with_context(|| {
+            format!(
+                "Failed to canonicalize path to include: \"{}\"",
+                potential_path.display()
+            )
+        })
+    };
+    let exists = |potential_path: &Path| potential_path.exists() && !potential_path.is_dir();
+
+    let maybe_resolved = if Path::new(path).is_absolute() {
+        let potential_path = Path::new(path);
+        trace!("Trying to resolve {} to {:?}", printer, potential_path);
+        exists(potential_path)
+            .then(|| canonicalize(potential_path))
+            .transpose()?
+            .map(|canonical_path| Resolved {
+                path: canonical_path,
+                origin: Origin::Elsewhere,
+            })
+    } else {
+        let current_dir_canonicalized = current_dir
+            .map(Path::canonicalize)
+            .transpose()
+            .context("failed to canonicalize current directory")?;
+        let search_dirs = search_path
+            .iter()
+            .enumerate()
+            .skip(start_idx)
+            .map(|(idx, dir)| (dir.as_path(), Origin::SearchDir { system, idx }));
+        current_dir_canonicalized
+            .as_deref()
+            .map(|dir| (dir, Origin::IncluderDir))
+            .into_iter()
+            .chain(search_dirs)
+            .find_map(|(include_dir, origin)| {
+                let potential_path = include_dir.join(path);
+                trace!("Trying to resolve {} to {:?}", printer, potential_path);
+                exists(&potential_path).then(|| {
+                    canonicalize(&potential_path).map(|canonical_path| Resolved {
+                        path: canonical_path,
+                        origin,
+                    })
                 })
             })
-        })
-        .transpose()?;
+            .transpose()?
+    };
 
-    let (left, right) = current_dir.map_or(('<', '>'), |_| ('"', '"'));
     if let Some(resolved) = &maybe_resolved {
-        debug!("Resolved {}{}{} to {:?}", left, path, right, resolved);
+        debug!("Resolved {} to {:?}", printer, resolved.path);
     } else {
-        debug!("Failed to resolve {}{}{}", left, path, right);
+        debug!("Failed to resolve {}", printer);
     }
 
     Ok(maybe_resolved)
@@ -70,8 +115,13 @@ impl IncludeResolver {
     pub fn new(
         mut quote_search_dirs: Vec<PathBuf>,
         mut system_search_dirs: Vec<PathBuf>,
+        mut system_header_dirs: Vec<PathBuf>,
     ) -> Result<Self> {
-        for path_vec in [&mut quote_search_dirs, &mut system_search_dirs] {
+        for path_vec in [
+            &mut quote_search_dirs,
+            &mut system_search_dirs,
+            &mut system_header_dirs,
+        ] {
             for path in path_vec {
                 *path = path.canonicalize().with_context(|| {
                     format!("Failed to canonicalize search path: \"{}\"", path.display())
@@ -81,23 +131,83 @@ impl IncludeResolver {
 
         debug!("Quote search dirs: {:#?}", quote_search_dirs);
         debug!("System search dirs: {:#?}", system_search_dirs);
+        debug!("System header dirs: {:#?}", system_header_dirs);
         Ok(Self {
             quote_search_paths: quote_search_dirs,
             system_search_paths: system_search_dirs,
+            system_header_dirs,
         })
     }
 
-    /// Tries to find the file referenced in a quote include statement.
+    /// Returns all search directories, starting with those for quote includes.
+    pub fn search_dirs(&self) -> impl Iterator<Item = &Path> {
+        let quote_dirs = self.quote_search_paths.iter();
+        let system_only_dirs = self
+            .system_search_paths
+            .iter()
+            .filter(move |dir| !self.quote_search_paths.contains(dir));
+        quote_dirs.chain(system_only_dirs).map(PathBuf::as_path)
+    }
+
+    /// Whether files found at `origin` are system headers.
     ///
-    /// If found, returns the canonicalized path to the file.
-    pub fn resolve_quote(&self, path: &str, current_dir: &Path) -> Result<Option<PathBuf>> {
-        resolve(path, &self.quote_search_paths, Some(current_dir))
+    /// This is only decided for files found in a search directory.
+    pub fn is_system_header_dir(&self, origin: Origin) -> bool {
+        match origin {
+            Origin::SearchDir { system, idx } => {
+                let search_path = if system {
+                    &self.system_search_paths
+                } else {
+                    &self.quote_search_paths
+                };
+                self.system_header_dirs.contains(&search_path[idx])
+            }
+            Origin::IncluderDir | Origin::Elsewhere => false,
+        }
+    }
+
+    /// Tries to find the file referenced in a quote include statement.
+    pub fn resolve_quote(&self, path: &str, current_dir: &Path) -> Result<Option<Resolved>> {
+        resolve(path, &self.quote_search_paths, false, Some(current_dir), 0)
     }
 
     /// Tries to find the file referenced in a system include statement.
+    pub fn resolve_system(&self, path: &str) -> Result<Option<Resolved>> {
+        resolve(path, &self.system_search_paths, true, None, 0)
+    }
+
+    /// Tries to find the file referenced in an `#include_next` statement, in a file found at
+    /// `origin`.
     ///
-    /// If found, returns the canonicalized path to the file.
-    pub fn resolve_system(&self, path: &str) -> Result<Option<PathBuf>> {
-        resolve(path, &self.system_search_paths, None)
+    /// Just like for GCC, the search continues after the search directory the including file was
+    /// found in. For files found relative to their includer, all search directories are searched,
+    /// while for other files, this works like a regular include.
+    pub fn resolve_next(
+        &self,
+        path: &str,
+        system: bool,
+        origin: Origin,
+        current_dir: &Path,
+    ) -> Result<Option<Resolved>> {
+        let (search_path, other_search_path) = if system {
+            (&self.system_search_paths, &self.quote_search_paths)
+        } else {
+            (&self.quote_search_paths, &self.system_search_paths)
+        };
+        let start_idx = match origin {
+            Origin::Elsewhere if system => return self.resolve_system(path),
+            Origin::Elsewhere => return self.resolve_quote(path, current_dir),
+            Origin::IncluderDir => 0,
+            Origin::SearchDir {
+                system: found_system,
+                idx,
+            } if found_system == system => idx + 1,
+            // Directories given for both kinds of includes are in both search paths
+            Origin::SearchDir { idx, .. } => search_path
+                .iter()
+                .position(|dir| *dir == other_search_path[idx])
+                .map_or(0, |pos| pos + 1),
+        };
+        resolve(path, search_path, system, None, start_idx)
     }
 }
@@ -0,0 +1,250 @@
+//! Mapping of the output lines back to the files they were copied from
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{annotations, process::Regexes};
+
+/// Version of the JSON format written by [`SourceMap::write`].
+const FORMAT_VERSION: u32 = 1;
+
+/// Consecutive output lines copied from consecutive lines of a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LineRange {
+    /// First output line of the range, starting at 1
+    pub output_line: usize,
+    /// Index of the file in [`SourceMap::files`]
+    pub file_idx: usize,
+    /// Line of the file the first output line was copied from, starting at 1
+    pub line: usize,
+    /// Number of lines in the range
+    pub len: usize,
+}
+
+impl Serialize for LineRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.output_line, self.file_idx, self.line, self.len).serialize(serializer)
+    }
+}
+
+/// Maps every line of the output which was copied from a file back to it.
+///
+/// Lines added by cpp-amalgamate, such as line directives, are not mapped.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct SourceMap {
+    /// Canonical paths of all files lines were copied from
+    pub files: Vec<PathBuf>,
+    /// All ranges of mapped lines, ordered by their output lines
+    pub ranges: Vec<LineRange>,
+}
+
+#[derive(Serialize)]
+struct JsonSourceMap<'a> {
+    version: u32,
+    #[serde(flatten)]
+    source_map: &'a SourceMap,
+}
+
+#[derive(Deserialize)]
+struct OwnedJsonSourceMap {
+    version: u32,
+    files: Vec<PathBuf>,
+    ranges: Vec<(usize, usize, usize, usize)>,
+}
+
+impl SourceMap {
+    /// Returns the file and line an output line was copied from, both starting at 1.
+    #[must_use]
+    pub fn lookup(&self, output_line: usize) -> Option<(&Path, usize)> {
+        let idx = self
+            .ranges
+            .partition_point(|range| range.output_line <= output_line)
+            .checked_sub(1)?;
+        let range = &self.ranges[idx];
+        let offset = output_line - range.output_line;
+        (offset < range.len).then(|| (self.files[range.file_idx].as_path(), range.line + offset))
+    }
+
+    /// Recovers the source map from the line directives in an amalgamated file.
+    ///
+    /// Both `#line` directives and GCC linemarkers are used. Lines before the first directive are
+    /// not mapped.
+    #[must_use]
+    pub fn from_line_directives(content: &str) -> Self {
+        let mut regexes = Regexes::new();
+        let mut builder = SourceMapBuilder::new();
+        let mut location: Option<(PathBuf, usize)> = None;
+        for line in content.split_inclusive('\n') {
+            if let Some((num, name)) = regexes.match_line_marker(line) {
+                let path = name.map(PathBuf::from).or_else(|| Some(location?.0));
+                location = path.map(|path| (path, num));
+                builder.added(line);
+            } else if let Some((path, num)) = &mut location {
+                builder.copied(path, *num, line);
+                *num += 1;
+            } else {
+                builder.added(line);
+            }
+        }
+        builder.finish()
+    }
+
+    /// Recovers the source map from the annotations in an amalgamated file.
+    ///
+    /// The paths of the files are the ones written in the annotations. Lines outside of any file
+    /// are not mapped.
+    #[must_use]
+    pub fn from_annotations(content: &str) -> Self {
+        annotations::recover_source_map(content)
+    }
+
+    /// Reads a source map written by [`write`](Self::write).
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from `reader` fails, or if it does not contain a valid source map.
+    pub fn read(reader: impl Read) -> Result<Self> {
+        let json: OwnedJsonSourceMap =
+            serde_json::from_reader(reader).context("Failed to read source map")?;
+        ensure!(
+            json.version == FORMAT_VERSION,
+            "Unsupported source map version {}",
+            json.version
+        );
+        let ranges: Vec<_> = json
+            .ranges
+            .into_iter()
+            .map(|(output_line, file_idx, line, len)| LineRange {
+                output_line,
+                file_idx,
+                line,
+                len,
+            })
+            .collect();
+        let valid = ranges.iter().all(|range| range.file_idx < json.files.len())
+            && ranges
+                .windows(2)
+                .all(|pair| pair[0].output_line + pair[0].len <= pair[1].output_line);
+        ensure!(valid, "Invalid ranges in source map");
+        Ok(Self {
+            files: json.files,
+            ranges,
+        })
+    }
+
+    /// Writes the source map as JSON.
+    ///
+    /// Each range is written as an array of its output line, file index, line, and length.
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to `writer` fails, or if a path is not valid UTF-8.
+    pub fn write(&self, writer: impl Write) -> Result<()> {
+        self.write_json(writer)
+            .context("Failed to write source map")
+    }
+
+    fn write_json(&self, mut writer: impl Write) -> Result<()> {
+        let json = JsonSourceMap {
+            version: FORMAT_VERSION,
+            source_map: self,
+        };
+        serde_json::to_writer(&mut writer, &json)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Records the source map while the output is written.
+#[derive(Debug)]
+pub struct SourceMapBuilder {
+    source_map: SourceMap,
+    file_indices: HashMap<PathBuf, usize>,
+    /// The output line currently being written
+    output_line: usize,
+    /// Whether nothing was written to the current output line yet
+    at_line_start: bool,
+}
+
+impl SourceMapBuilder {
+    pub fn new() -> Self {
+        Self {
+            source_map: SourceMap::default(),
+            file_indices: HashMap::new(),
+            output_line: 1,
+            at_line_start: true,
+        }
+    }
+
+    /// Records text copied from the file, starting at the given line.
+    ///
+    /// If the previous output did not end with a newline, the first line is already mapped.
+    pub fn copied(&mut self, path: &Path, first_line: usize, text: &str) {
+        let file_idx = if let Some(&idx) = self.file_indices.get(path) {
+            idx
+        } else {
+            let idx = self.source_map.files.len();
+            self.source_map.files.push(path.to_owned());
+            self.file_indices.insert(path.to_owned(), idx);
+            idx
+        };
+
+        for (offset, line) in text.split_inclusive('\n').enumerate() {
+            if self.at_line_start {
+                self.map_line(file_idx, first_line + offset);
+            }
+            self.advance(line);
+        }
+    }
+
+    /// Records text added by cpp-amalgamate, which is not mapped.
+    pub fn added(&mut self, text: &str) {
+        for line in text.split_inclusive('\n') {
+            self.advance(line);
+        }
+    }
+
+    /// Returns whether the output written so far ends with a newline.
+    pub fn at_line_start(&self) -> bool {
+        self.at_line_start
+    }
+
+    fn map_line(&mut self, file_idx: usize, line: usize) {
+        if let Some(last) = self.source_map.ranges.last_mut() {
+            let extends_last = last.file_idx == file_idx
+                && last.output_line + last.len == self.output_line
+                && last.line + last.len == line;
+            if extends_last {
+                last.len += 1;
+                return;
+            }
+        }
+        self.source_map.ranges.push(LineRange {
+            output_line: self.output_line,
+            file_idx,
+            line,
+            len: 1,
+        });
+    }
+
+    fn advance(&mut self, line: &str) {
+        if line.ends_with('\n') {
+            self.output_line += 1;
+            self.at_line_start = true;
+        } else {
+            self.at_line_start = false;
+        }
+    }
+
+    pub fn finish(self) -> SourceMap {
+        self.source_map
+    }
+}
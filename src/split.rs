@@ -0,0 +1,213 @@
+//! Reconstruction of the files an amalgamation was created from
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use similar::{ChangeTag, TextDiff};
+
+use crate::{
+    annotations,
+    comments::{self, CommentStripping},
+    process,
+    source_map::SourceMap,
+};
+
+/// The contents of a file reconstructed from an amalgamation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SplitFile {
+    /// Path of the file as recorded in the source map
+    pub path: PathBuf,
+    /// Current contents of the file, or `None` if it does not exist
+    pub original: Option<String>,
+    /// Contents of the file according to the amalgamation
+    pub content: String,
+}
+
+impl SplitFile {
+    /// Returns whether the reconstructed contents differ from the current ones.
+    #[must_use]
+    pub fn is_changed(&self) -> bool {
+        self.original.as_deref() != Some(&self.content)
+    }
+
+    /// Returns a unified diff from the current to the reconstructed contents.
+    ///
+    /// Both sides of the diff are labeled with the path of the file, so that it can be applied
+    /// using `patch -p0`.
+    #[must_use]
+    pub fn unified_diff(&self) -> String {
+        let name = self.path.to_string_lossy();
+        let original = self.original.as_deref().unwrap_or("");
+        TextDiff::from_lines(original, &self.content)
+            .unified_diff()
+            .header(&name, &name)
+            .to_string()
+    }
+}
+
+/// Collects the lines copied from each file, in the order of the files in the source map.
+///
+/// For files inlined multiple times, only the lines of their first inclusion are used. Line origin
+/// annotations are removed from the lines.
+fn lines_by_file<'a>(amalgamated: &'a str, source_map: &SourceMap) -> Vec<Vec<Cow<'a, str>>> {
+    let lines: Vec<_> = amalgamated.split_inclusive('\n').collect();
+    let mut lines_by_file = vec![Vec::new(); source_map.files.len()];
+    // First line of the last range of each file, or `None` once the file was included again.
+    // Since lines might have been added in the amalgamation, only the first lines of the ranges are
+    // reliable.
+    let mut last_lines = HashMap::new();
+    for range in &source_map.ranges {
+        let last_line = last_lines.entry(range.file_idx).or_insert(Some(0));
+        match last_line {
+            Some(line) if *line < range.line => *line = range.line,
+            _ => {
+                *last_line = None;
+                continue;
+            }
+        }
+
+        let start = (range.output_line - 1).min(lines.len());
+        let end = (start + range.len).min(lines.len());
+        let path = &source_map.files[range.file_idx];
+        lines_by_file[range.file_idx].extend(lines[start..end].iter().map(|&line| {
+            annotations::split_line_origin(line, path)
+                .map_or(Cow::from(line), |(line, _)| line.into())
+        }));
+    }
+    lines_by_file
+}
+
+/// Guesses how comments were stripped from the lines copied from a file.
+///
+/// Comments are assumed to be stripped if the current contents contain comments which would be
+/// removed, but none of them are left in the copied lines.
+fn detect_comment_stripping(original: &str, copied: &str) -> CommentStripping {
+    let has_comments = comments::has_removable_comments;
+    if has_comments(original, CommentStripping::KeepLicenses) {
+        if has_comments(copied, CommentStripping::KeepLicenses) {
+            CommentStripping::None
+        } else if has_comments(copied, CommentStripping::All) {
+            CommentStripping::KeepLicenses
+        } else {
+            CommentStripping::All
+        }
+    } else if has_comments(original, CommentStripping::All)
+        && !has_comments(copied, CommentStripping::All)
+    {
+        CommentStripping::All
+    } else {
+        CommentStripping::None
+    }
+}
+
+/// Merges the lines copied from a file into its current contents.
+///
+/// Lines only present in the amalgamation were added or changed there. Lines only present in the
+/// current contents were either removed by the amalgamation, such as include statements and
+/// `#pragma once`, in which case they are restored, or were deleted from the amalgamation.
+///
+/// If comments were stripped from the amalgamation, the copied lines are compared to the current
+/// contents with comments stripped in the same way, and the comments of unchanged lines are
+/// restored.
+fn merge(original: &str, copied_lines: &[Cow<'_, str>]) -> String {
+    let removable = process::removable_lines(original);
+    let copied = copied_lines.concat();
+    let original_lines: Vec<_> = original.split_inclusive('\n').collect();
+    let mode = detect_comment_stripping(original, &copied);
+    let stripped_lines = if mode == CommentStripping::None {
+        original_lines.iter().map(|&line| line.to_owned()).collect()
+    } else {
+        debug!("Comments were stripped using {:?}", mode);
+        comments::strip_comments(original, mode)
+    };
+
+    // Lines consisting only of comments were left out of the amalgamation, so they are left out of
+    // the comparison as well and restored in front of the following line
+    let (compared_indices, compared_lines): (Vec<_>, Vec<_>) = stripped_lines
+        .iter()
+        .enumerate()
+        .filter(|&(idx, stripped)| {
+            mode == CommentStripping::None
+                || !comments::is_comment_only(original_lines[idx], stripped)
+        })
+        .map(|(idx, stripped)| (idx, stripped.as_str()))
+        .unzip();
+    let copied_split: Vec<_> = copied.split_inclusive('\n').collect();
+    let diff = TextDiff::from_slices(&compared_lines, &copied_split);
+
+    let mut merged = String::with_capacity(copied.len());
+    let mut next_original = 0;
+    for change in diff.iter_all_changes() {
+        if let Some(idx) = change.old_index().map(|idx| compared_indices[idx]) {
+            for &skipped in &original_lines[next_original..idx] {
+                merged.push_str(skipped);
+            }
+            next_original = idx + 1;
+            match change.tag() {
+                ChangeTag::Equal => merged.push_str(original_lines[idx]),
+                _ if removable.get(idx).copied().unwrap_or(false) => {
+                    merged.push_str(original_lines[idx]);
+                }
+                _ => {}
+            }
+        } else {
+            merged.push_str(change.value());
+        }
+    }
+    for &skipped in &original_lines[next_original..] {
+        merged.push_str(skipped);
+    }
+    merged
+}
+
+/// Reconstructs the files an amalgamation was created from.
+///
+/// The lines of each file are located using the source map, which must match the given contents
+/// of the amalgamation. Lines removed by the amalgamation are restored from the current contents of
+/// the files.
+///
+/// # Errors
+///
+/// Fails if one of the files exists but cannot be read.
+pub fn split(amalgamated: &str, source_map: &SourceMap) -> Result<Vec<SplitFile>> {
+    let lines_by_file = lines_by_file(amalgamated, source_map);
+    source_map
+        .files
+        .iter()
+        .zip(lines_by_file)
+        .map(|(path, lines)| {
+            let original = read_if_exists(path)?;
+            let content = if let Some(original) = &original {
+                merge(original, &lines)
+            } else {
+                warn!(
+                    "\"{}\" does not exist, lines removed by the amalgamation are not restored",
+                    path.display()
+                );
+                lines.concat()
+            };
+            debug!("Reconstructed {} lines of {:?}", lines.len(), path);
+            Ok(SplitFile {
+                path: path.clone(),
+                original,
+                content,
+            })
+        })
+        .collect()
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => {
+            Err(error).with_context(|| format!("Failed to read file \"{}\"", path.display()))
+        }
+    }
+}
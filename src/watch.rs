@@ -0,0 +1,83 @@
+//! Re-running the amalgamation whenever one of the files it read changes
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::PathBuf,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use log::{debug, error, info};
+
+/// How often the watched files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long the watched files must stay unchanged before running again.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Modification times and sizes of all watched paths, with `None` for paths which do not exist.
+///
+/// The size is included since file systems with a coarse timestamp resolution might not update the
+/// modification time for quick successive changes.
+type Snapshot = HashMap<PathBuf, Option<(SystemTime, u64)>>;
+
+fn snapshot(paths: &BTreeSet<PathBuf>) -> Snapshot {
+    paths
+        .iter()
+        .map(|path| {
+            let state = fs::metadata(path)
+                .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+                .ok();
+            (path.clone(), state)
+        })
+        .collect()
+}
+
+/// Blocks until any of the paths changes compared to the snapshot, and then stays unchanged for
+/// the debounce duration.
+fn wait_for_change(paths: &BTreeSet<PathBuf>, initial: &Snapshot) {
+    let mut current = loop {
+        thread::sleep(POLL_INTERVAL);
+        let current = snapshot(paths);
+        if current != *initial {
+            break current;
+        }
+    };
+
+    loop {
+        thread::sleep(DEBOUNCE);
+        let next = snapshot(paths);
+        if next == current {
+            return;
+        }
+        current = next;
+    }
+}
+
+/// Calls `run` whenever one of the paths it watched during its previous call changes.
+///
+/// `run` adds all paths whose changes could change its result to the given set. Errors are
+/// reported, after which the paths watched before are watched as well, since `run` might not have
+/// gotten to add all of them.
+pub fn watch(mut run: impl FnMut(&mut BTreeSet<PathBuf>) -> Result<()>) -> ! {
+    let mut watched = BTreeSet::new();
+    loop {
+        let mut paths = BTreeSet::new();
+        match run(&mut paths) {
+            Ok(()) => watched = paths,
+            Err(error) => {
+                error!("{:#}", error);
+                watched.extend(paths);
+            }
+        }
+
+        // Taken before announcing that the files are watched, so that every change made after the
+        // announcement is detected
+        let initial = snapshot(&watched);
+        info!("Watching {} files for changes", watched.len());
+        debug!("Watched files: {:#?}", watched);
+        wait_for_change(&watched, &initial);
+        info!("Change detected, running again");
+    }
+}
@@ -0,0 +1,142 @@
+use crate::util;
+
+use anyhow::Result;
+use assert_fs::{prelude::*, NamedTempFile};
+use indoc::{formatdoc, indoc};
+
+#[test]
+fn annotate_includes() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {"
+            #include <a.hpp>
+            #include <a.hpp>
+            #include <c.hpp>
+            #include <missing.hpp>
+            arst
+        "})?
+        .search_dir("-d", [("a.hpp", "#pragma once\nqwfp\n"), ("c.hpp", "")])?;
+    let source = builder.source_files[0].path();
+    let source_path = source.canonicalize()?;
+    let a_hpp = builder.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .path()
+        .canonicalize()?;
+
+    builder
+        .command()
+        .arg("--annotate")
+        .args(["--filter", "**/c.hpp"])
+        .assert()
+        .success()
+        .stdout(formatdoc! {"
+            // begin {source} ({source_path})
+            // begin <a.hpp> ({a_hpp})
+            // #pragma once removed
+            qwfp
+            // end <a.hpp>
+            // #include <a.hpp> skipped: already inlined
+            // #include <c.hpp> kept: filtered by '**/c.hpp'
+            #include <c.hpp>
+            // #include <missing.hpp> kept: not found
+            #include <missing.hpp>
+            arst
+            // end {source}
+            ",
+            source = source.display(),
+            source_path = source_path.display(),
+            a_hpp = a_hpp.display()
+        });
+    Ok(())
+}
+
+#[test]
+fn annotate_lines() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {"
+            arst
+            #include <a.hpp>
+
+            /* arst
+            */ arst
+        "})?
+        .search_dir("-d", [("a.hpp", "qwfp\n")])?;
+    let source = builder.source_files[0].path();
+    let source_path = source.canonicalize()?;
+    let a_hpp = builder.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .path()
+        .canonicalize()?;
+
+    builder
+        .command()
+        .arg("--annotate=lines")
+        .assert()
+        .success()
+        .stdout(formatdoc! {"
+            // begin {source} ({source_path})
+            arst // {source_path}:1
+            // begin <a.hpp> ({a_hpp})
+            qwfp // {a_hpp}:1
+            // end <a.hpp>
+
+            /* arst
+            */ arst // {source_path}:5
+            // end {source}
+            ",
+            source = source.display(),
+            source_path = source_path.display(),
+            a_hpp = a_hpp.display()
+        });
+    Ok(())
+}
+
+#[test]
+fn locate_using_annotations() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {"
+            #include <a.hpp>
+            #include <b.hpp>
+            arst
+        "})?
+        .search_dir(
+            "-d",
+            [
+                ("a.hpp", "#pragma once\n#include <b.hpp>\nqwfp\n"),
+                ("b.hpp", "#pragma once\nzxcv\n"),
+            ],
+        )?;
+    let source = builder.source_files[0].path().canonicalize()?;
+    let a_hpp = builder.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .path()
+        .canonicalize()?;
+    let output_file = NamedTempFile::new("out.cpp")?;
+    builder
+        .command()
+        .arg("--annotate")
+        .arg("-o")
+        .arg(output_file.path())
+        .assert()
+        .success();
+
+    // Line 8 is qwfp, following the inlined b.hpp
+    util::command()
+        .arg("locate")
+        .arg(output_file.path())
+        .arg("8")
+        .assert()
+        .success()
+        .stdout(format!("{}:3\n", a_hpp.display()));
+    // Line 11 is arst, following the skipped include of b.hpp
+    util::command()
+        .arg("locate")
+        .arg(output_file.path())
+        .arg("11")
+        .assert()
+        .success()
+        .stdout(format!("{}:3\n", source.display()));
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use crate::util;
+
+use anyhow::Result;
+use assert_fs::{prelude::*, TempDir};
+use indoc::indoc;
+
+#[test]
+fn search_dirs_and_macros() -> Result<()> {
+    let dir = TempDir::new()?;
+    dir.child("build/compile_commands.json")
+        .write_str(indoc! {r#"
+            [
+                {
+                    "directory": "..",
+                    "file": "src/a.cpp",
+                    "command": "c++ -Iinclude -iquote 'quote dir' -DWITH_B=1 -UWITH_C -c src/a.cpp"
+                },
+                {
+                    "directory": "..",
+                    "file": "src/b.cpp",
+                    "arguments": ["c++", "-isystem", "system", "-D", "WITH_C", "-c", "src/b.cpp"]
+                }
+            ]
+        "#})?;
+    dir.child("src/a.cpp").write_str(indoc! {r#"
+        #include "q.hpp"
+        #include <x.hpp>
+        #if WITH_B
+        #include <b.hpp>
+        #endif
+        #ifdef WITH_C
+        #include <c.hpp>
+        #endif
+    "#})?;
+    dir.child("src/b.cpp").write_str("#include <s.hpp>\n")?;
+    dir.child("quote dir/q.hpp").write_str("// q\n")?;
+    dir.child("include/x.hpp").write_str("// x\n")?;
+    dir.child("include/b.hpp").write_str("// b\n")?;
+    dir.child("include/c.hpp").write_str("// c\n")?;
+    dir.child("system/s.hpp").write_str("// s\n")?;
+
+    util::command()
+        .current_dir(dir.path())
+        .args([
+            "--compile-commands",
+            "build/compile_commands.json",
+            "src/a.cpp",
+        ])
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            // q
+            // x
+            #if WITH_B
+            // b
+            #endif
+            #ifdef WITH_C
+            #include <c.hpp>
+            #endif
+        "});
+    util::command()
+        .current_dir(dir.path())
+        .args([
+            "--compile-commands",
+            "build/compile_commands.json",
+            "src/b.cpp",
+        ])
+        .assert()
+        .success()
+        .stdout("// s\n");
+    Ok(())
+}
+
+#[test]
+fn flags_only_apply_to_their_source_file() -> Result<()> {
+    let dir = TempDir::new()?;
+    dir.child("compile_commands.json").write_str(indoc! {r#"
+        [
+            {"directory": ".", "file": "a.cpp", "command": "c++ -Iinclude -UWITH_C -c a.cpp"},
+            {"directory": ".", "file": "b.cpp", "command": "c++ -isystem system -DWITH_C -c b.cpp"}
+        ]
+    "#})?;
+    let source = indoc! {"
+        #include <x.hpp>
+        #ifdef WITH_C
+        #include <c.hpp>
+        #endif
+    "};
+    dir.child("a.cpp").write_str(source)?;
+    dir.child("b.cpp").write_str(source)?;
+    dir.child("include/x.hpp").write_str("// x\n")?;
+    dir.child("system/c.hpp").write_str("// c\n")?;
+
+    util::command()
+        .current_dir(dir.path())
+        .args([
+            "--compile-commands",
+            "compile_commands.json",
+            "a.cpp",
+            "b.cpp",
+        ])
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            // x
+            #ifdef WITH_C
+            #include <c.hpp>
+            #endif
+            #include <x.hpp>
+            #ifdef WITH_C
+            // c
+            #endif
+        "});
+    Ok(())
+}
+
+#[test]
+fn missing_entry() -> Result<()> {
+    let dir = TempDir::new()?;
+    dir.child("compile_commands.json").write_str("[]")?;
+    dir.child("main.cpp").write_str("#include <a.hpp>\n")?;
+    dir.child("include/a.hpp").write_str("// a\n")?;
+    util::command()
+        .current_dir(dir.path())
+        .args(["--compile-commands", "compile_commands.json", "main.cpp"])
+        .assert()
+        .success()
+        .stdout("#include <a.hpp>\n");
+    Ok(())
+}
@@ -0,0 +1,332 @@
+use crate::util;
+
+use anyhow::Result;
+use indoc::indoc;
+
+#[test]
+fn includes_in_dead_branches() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #define MODE 2
+            #if MODE == 1
+            #include <a.hpp>
+            #elif MODE == 2
+            #include <b.hpp>
+            #else
+            #include <c.hpp>
+            #endif
+        "})?
+        .search_dir(
+            "-d",
+            [
+                ("a.hpp", "// a\n"),
+                ("b.hpp", "// b\n"),
+                ("c.hpp", "// c\n"),
+            ],
+        )?
+        .command()
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            #define MODE 2
+            #if MODE == 1
+            #include <a.hpp>
+            #elif MODE == 2
+            // b
+            #else
+            #include <c.hpp>
+            #endif
+        "});
+    Ok(())
+}
+
+#[test]
+fn same_include_in_separate_branches() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #ifdef FOO
+            #include <a.hpp>
+            #endif
+            #ifndef FOO
+            #include <a.hpp>
+            #endif
+        "})?
+        .search_dir("-d", [("a.hpp", "// a\n")])?
+        .command()
+        .args(["-U", "FOO"])
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            #ifdef FOO
+            #include <a.hpp>
+            #endif
+            #ifndef FOO
+            // a
+            #endif
+        "});
+    Ok(())
+}
+
+#[test]
+fn cli_defines() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {"
+            #if defined(A) && B == 3
+            #include <a.hpp>
+            #endif
+        "})?
+        .search_dir("-d", [("a.hpp", "// a\n")])?;
+
+    let inlined = indoc! {"
+        #if defined(A) && B == 3
+        // a
+        #endif
+    "};
+    let not_inlined = indoc! {"
+        #if defined(A) && B == 3
+        #include <a.hpp>
+        #endif
+    "};
+    let cases: [(&[&str], &str); 4] = [
+        (&["-D", "A", "-D", "B=3"], inlined),
+        (&["--define", "A", "--define", "B=2"], not_inlined),
+        (&["-D", "A", "-D", "B=3", "-U", "A"], not_inlined),
+        (&["-U", "A", "-D", "A", "-D", "B=3"], inlined),
+    ];
+    for (args, expected) in cases {
+        builder
+            .command()
+            .args(args)
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+
+    util::builder()
+        .source_file("")?
+        .command()
+        .args(["-D", "1A"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn pragma_once_in_dead_branch() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #include <a.hpp>
+            #include <a.hpp>
+        "})?
+        .search_dir("-d", [("a.hpp", "#if 0\n#pragma once\n#endif\n// a\n")])?
+        .command()
+        .arg("--multi-include-unguarded")
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            #if 0
+            #pragma once
+            #endif
+            // a
+            #if 0
+            #pragma once
+            #endif
+            // a
+        "});
+    Ok(())
+}
+
+#[test]
+fn undecidable_branches() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #if FOO(1)
+            #define A
+            #include <a.hpp>
+            #else
+            #include <b.hpp>
+            #endif
+            #ifdef A
+            #include <a.hpp>
+            #endif
+            #if 1 || FOO(1)
+            #include <b.hpp>
+            #endif
+        "})?
+        .search_dir("-d", [("a.hpp", "// a\n"), ("b.hpp", "// b\n")])?
+        .command()
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            #if FOO(1)
+            #define A
+            #include <a.hpp>
+            #else
+            #include <b.hpp>
+            #endif
+            #ifdef A
+            #include <a.hpp>
+            #endif
+            #if 1 || FOO(1)
+            // b
+            #endif
+        "});
+    Ok(())
+}
+
+#[test]
+fn predefined_macros() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {"
+            #if __cplusplus >= 201703L
+            #include <a.hpp>
+            #else
+            #include <b.hpp>
+            #endif
+            #ifdef _WIN32
+            #include <a.hpp>
+            #endif
+        "})?
+        .search_dir("-d", [("a.hpp", "// a\n"), ("b.hpp", "// b\n")])?;
+
+    builder.command().assert().success().stdout(indoc! {"
+        #if __cplusplus >= 201703L
+        #include <a.hpp>
+        #else
+        #include <b.hpp>
+        #endif
+        #ifdef _WIN32
+        #include <a.hpp>
+        #endif
+    "});
+    builder
+        .command()
+        .args(["-D", "__cplusplus=201402L", "-U", "_WIN32"])
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            #if __cplusplus >= 201703L
+            #include <a.hpp>
+            #else
+            // b
+            #endif
+            #ifdef _WIN32
+            #include <a.hpp>
+            #endif
+        "});
+    Ok(())
+}
+
+#[test]
+fn nested_conditionals_across_files() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #include <config.hpp>
+            #if ENABLE_A
+            #  if ENABLE_B
+            #    include <b.hpp>
+            #  else
+            #    include <a.hpp>
+            #  endif
+            #endif
+        "})?
+        .search_dir(
+            "-d",
+            [
+                (
+                    "config.hpp",
+                    "#define ENABLE_A 1\n#define ENABLE_B ENABLE_A - 1\n",
+                ),
+                ("a.hpp", "// a\n"),
+                ("b.hpp", "// b\n"),
+            ],
+        )?
+        .command()
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            #define ENABLE_A 1
+            #define ENABLE_B ENABLE_A - 1
+            #if ENABLE_A
+            #  if ENABLE_B
+            #    include <b.hpp>
+            #  else
+            // a
+            #  endif
+            #endif
+        "});
+    Ok(())
+}
+
+#[test]
+fn conditional_expressions() -> Result<()> {
+    let conditions = [
+        "(2 + 3) * 4 == 20 && 7 / 2 == 3 && 7 % 4 == 3",
+        "1 << 4 == 0x10 && 0b101 == 5 && 010 == 8 && 1'000 == 1000",
+        "-1 < 0 && !(-1 < 0u) && ~0u == 18446744073709551615u",
+        "'a' == 97 && '\\n' == 10",
+        "(1 ? 2 : 3) == 2 && (0 ? 2 : 3) == 3",
+        "true && !false && not 0 and (1 bitor 2) == 3",
+        "UNDEFINED == 0 && !defined UNDEFINED",
+        "1 || 1 / 0",
+    ];
+    for condition in conditions {
+        util::builder()
+            .source_file(&format!("#if {}\n#include <a.hpp>\n#endif\n", condition))?
+            .search_dir("-d", [("a.hpp", "// a\n")])?
+            .command()
+            .args(["-U", "UNDEFINED"])
+            .assert()
+            .success()
+            .stdout(format!("#if {}\n// a\n#endif\n", condition));
+    }
+    Ok(())
+}
+
+#[test]
+fn has_include() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {r#"
+            #if defined(__has_include) && __has_include(<optional>)
+            #include <optional>
+            #else
+            #include <experimental/optional.h>
+            #endif
+            #if __has_include("missing.hpp") || __has_include( <sys/missing.h> )
+            #include <a.hpp>
+            #elif __has_include_next(<a.hpp>)
+            #include <b.hpp>
+            #endif
+            #if __has_include(HEADER)
+            #include <a.hpp>
+            #endif
+        "#})?
+        .search_dir(
+            "-d",
+            [
+                ("optional", "// optional\n"),
+                ("experimental/optional.h", "// experimental\n"),
+                ("a.hpp", "// a\n"),
+                ("b.hpp", "// b\n"),
+            ],
+        )?
+        .command()
+        .assert()
+        .success()
+        .stdout(indoc! {r#"
+            #if defined(__has_include) && __has_include(<optional>)
+            // optional
+            #else
+            #include <experimental/optional.h>
+            #endif
+            #if __has_include("missing.hpp") || __has_include( <sys/missing.h> )
+            #include <a.hpp>
+            #elif __has_include_next(<a.hpp>)
+            // b
+            #endif
+            #if __has_include(HEADER)
+            #include <a.hpp>
+            #endif
+        "#});
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use crate::util;
+
+use anyhow::Result;
+use assert_fs::{prelude::*, TempDir};
+use indoc::indoc;
+
+fn project() -> Result<TempDir> {
+    let dir = TempDir::new()?;
+    dir.child("cpp-amalgamate.toml").write_str(indoc! {r#"
+        [defaults]
+        dir = ["include"]
+        unresolvable-include = "error"
+
+        [targets.app]
+        inputs = ["src/app.cpp"]
+        output = "out/app.cpp"
+
+        [targets.lib]
+        inputs = ["src/lib.cpp"]
+        output = "out/lib.cpp"
+        filter = ["**/b.hpp"]
+        define = ["WITH_C"]
+    "#})?;
+    dir.child("out").create_dir_all()?;
+    dir.child("src/app.cpp")
+        .write_str("#include <a.hpp>\n#include \"b.hpp\"\n")?;
+    dir.child("src/lib.cpp")
+        .write_str("#include <b.hpp>\n#ifdef WITH_C\n#include <c.hpp>\n#endif\n")?;
+    dir.child("include/a.hpp").write_str("// a\n")?;
+    dir.child("include/b.hpp").write_str("// b\n")?;
+    dir.child("include/c.hpp").write_str("// c\n")?;
+    Ok(dir)
+}
+
+#[test]
+fn building_all_targets() -> Result<()> {
+    let dir = project()?;
+    util::command()
+        .current_dir(dir.child("src").path())
+        .arg("build")
+        .assert()
+        .success()
+        .stdout("");
+    dir.child("out/app.cpp").assert("// a\n// b\n");
+    dir.child("out/lib.cpp")
+        .assert("#include <b.hpp>\n#ifdef WITH_C\n// c\n#endif\n");
+    Ok(())
+}
+
+#[test]
+fn building_selected_targets() -> Result<()> {
+    let dir = project()?;
+    util::command()
+        .current_dir(dir.path())
+        .args(["build", "lib"])
+        .assert()
+        .success();
+    dir.child("out/app.cpp").assert(predicates::path::missing());
+    dir.child("out/lib.cpp")
+        .assert(predicates::str::contains("// c"));
+
+    util::command()
+        .current_dir(dir.path())
+        .args(["build", "unknown"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn cli_overrides_config() -> Result<()> {
+    let dir = project()?;
+    util::command()
+        .current_dir(dir.path())
+        .args(["build", "app", "-o", "custom.cpp", "--filter", "**/*.hpp"])
+        .args([
+            "--filter-quote",
+            "!**/b.hpp",
+            "--unresolvable-include",
+            "ignore",
+        ])
+        .assert()
+        .success();
+    dir.child("custom.cpp").assert("#include <a.hpp>\n// b\n");
+    dir.child("out/app.cpp").assert(predicates::path::missing());
+
+    util::command()
+        .current_dir(dir.path())
+        .args(["-o", "out.cpp", "build"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn cli_search_dirs_and_switches_override_config() -> Result<()> {
+    let dir = project()?;
+    dir.child("cpp-amalgamate.toml").write_str(indoc! {r#"
+        [defaults]
+        dir = ["include"]
+        line-directives = true
+        strip-header-guards = true
+
+        [targets.app]
+        inputs = ["src/app.cpp"]
+    "#})?;
+    dir.child("other/a.hpp")
+        .write_str("#ifndef A\n#define A\n// other a\n#endif\n")?;
+
+    util::command()
+        .current_dir(dir.path())
+        .args(["build", "app", "-d", "other"])
+        .args(["--no-line-directives", "--no-strip-header-guards"])
+        .assert()
+        .success()
+        .stdout("#ifndef A\n#define A\n// other a\n#endif\n// b\n");
+    util::command()
+        .current_dir(dir.path())
+        .args(["build", "app", "--no-line-directives", "--line-directives"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("#line 1 "));
+    Ok(())
+}
+
+#[test]
+fn defaults_apply_without_targets() -> Result<()> {
+    let dir = project()?;
+    util::command()
+        .current_dir(dir.path())
+        .arg("src/app.cpp")
+        .assert()
+        .success()
+        .stdout("// a\n// b\n");
+    Ok(())
+}
+
+#[test]
+fn invalid_config() -> Result<()> {
+    let dir = TempDir::new()?;
+    let config = dir.child("cpp-amalgamate.toml");
+    let source = dir.child("src.cpp");
+    source.write_str("")?;
+    for content in [
+        "[defaults]\nunknown-key = 1\n",
+        "[defaults]\nunresolvable-include = \"maybe\"\n",
+        "[defaults]\ninputs = [\"src.cpp\"]\n",
+    ] {
+        config.write_str(content)?;
+        util::command()
+            .current_dir(dir.path())
+            .arg(source.path())
+            .assert()
+            .failure();
+    }
+
+    util::command()
+        .current_dir(TempDir::new()?.path())
+        .arg("build")
+        .assert()
+        .failure();
+    Ok(())
+}
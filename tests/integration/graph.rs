@@ -0,0 +1,118 @@
+use std::{fs, path::Path};
+
+use crate::util;
+
+use anyhow::Result;
+use assert_fs::{prelude::*, NamedTempFile};
+use indoc::indoc;
+use serde_json::{json, Value};
+
+fn include(from: &Path, to: Option<&Path>, spelling: &str, line: usize, decision: &str) -> Value {
+    let kind = if spelling.starts_with('<') {
+        "system"
+    } else if spelling.starts_with('"') {
+        "quote"
+    } else {
+        "computed"
+    };
+    json!({
+        "from": from,
+        "to": to,
+        "spelling": spelling,
+        "kind": kind,
+        "line": line,
+        "decision": decision,
+    })
+}
+
+fn setup() -> Result<util::TestSetupBuilder> {
+    util::builder()
+        .source_file(indoc! {r#"
+            #include <a.hpp>
+            #include "b.hpp"
+            #include <a.hpp>
+            #include <missing.hpp>
+            #if 0
+            #include <a.hpp>
+            #endif
+            #include CONFIG_HEADER
+            #if __cplusplus
+            #include <a.hpp>
+            #endif
+        "#})?
+        .search_dir("-d", [("a.hpp", "#include <b.hpp>\n"), ("b.hpp", "// b\n")])
+}
+
+#[test]
+fn json_graph() -> Result<()> {
+    let setup = setup()?;
+    let source = setup.source_files[0].path().canonicalize()?;
+    let dir = setup.search_dirs[0].1.path().canonicalize()?;
+    let header_a = dir.join("a.hpp");
+    let header_b = dir.join("b.hpp");
+    let graph_file = NamedTempFile::new("graph.json")?;
+
+    setup
+        .command()
+        .args(["--filter-quote", "**/b.hpp", "--graph"])
+        .arg(graph_file.path())
+        .assert()
+        .success();
+
+    let graph: Value = serde_json::from_str(&fs::read_to_string(graph_file.path())?)?;
+    assert_eq!(
+        graph,
+        json!({
+            "includes": [
+                include(&source, Some(&header_a), "<a.hpp>", 1, "inlined"),
+                include(&header_a, Some(&header_b), "<b.hpp>", 1, "inlined"),
+                include(&source, Some(&header_b), "\"b.hpp\"", 2, "filtered"),
+                include(&source, Some(&header_a), "<a.hpp>", 3, "skipped"),
+                include(&source, None, "<missing.hpp>", 4, "unresolved"),
+                include(&source, None, "<a.hpp>", 6, "inactive"),
+                include(&source, None, "CONFIG_HEADER", 8, "unexpanded"),
+                include(&source, None, "<a.hpp>", 10, "undecided"),
+            ]
+        })
+    );
+    Ok(())
+}
+
+#[test]
+fn dot_graph() -> Result<()> {
+    let setup = setup()?;
+    let source = setup.source_files[0].path().canonicalize()?;
+    let dir = setup.search_dirs[0].1.path().canonicalize()?;
+    let header_a = dir.join("a.hpp");
+    let header_b = dir.join("b.hpp");
+    let graph_file = NamedTempFile::new("graph.txt")?;
+
+    setup
+        .command()
+        .args(["--graph-format", "dot", "--graph"])
+        .arg(graph_file.path())
+        .assert()
+        .success();
+
+    let (source, header_a, header_b) = (source.display(), header_a.display(), header_b.display());
+    graph_file.assert(format!(
+        indoc! {r#"
+            digraph includes {{
+                "{0}" -> "{1}" [label="<a.hpp>:1 (inlined)", style=solid];
+                "{1}" -> "{2}" [label="<b.hpp>:1 (inlined)", style=solid];
+                "{0}" -> "{2}" [label="\"b.hpp\":2 (skipped)", style=dashed];
+                "{0}" -> "{1}" [label="<a.hpp>:3 (skipped)", style=dashed];
+                "<missing.hpp>" [style=dashed];
+                "{0}" -> "<missing.hpp>" [label="<missing.hpp>:4 (unresolved)", style=dashed];
+                "<a.hpp>" [style=dashed];
+                "{0}" -> "<a.hpp>" [label="<a.hpp>:6 (inactive)", style=dashed];
+                "CONFIG_HEADER" [style=dashed];
+                "{0}" -> "CONFIG_HEADER" [label="CONFIG_HEADER:8 (unexpanded)", style=dashed];
+                "<a.hpp>" [style=dashed];
+                "{0}" -> "<a.hpp>" [label="<a.hpp>:10 (undecided)", style=dashed];
+            }}
+        "#},
+        source, header_a, header_b
+    ));
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use crate::util;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use assert_fs::prelude::*;
@@ -117,6 +117,50 @@ fn weird_include_statements() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn computed_includes() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {r#"
+            #define CONFIG "config.hpp" // comment
+            #include CONFIG
+            #define INDIRECT PLATFORM
+            #include INDIRECT
+            #include UNDEFINED
+            #define FUNCTION(x) <a.hpp>
+            #include FUNCTION(1)
+            #if UNKNOWN(1)
+            #define MAYBE <a.hpp>
+            #endif
+            #include MAYBE
+        "#})?
+        .search_dir(
+            "-d",
+            [
+                ("config.hpp", "// config\n"),
+                ("platform/linux.hpp", "// linux\n"),
+                ("a.hpp", "// a\n"),
+            ],
+        )?
+        .command()
+        .arg("-DPLATFORM=<platform/linux.hpp>")
+        .assert()
+        .success()
+        .stdout(indoc! {r#"
+            #define CONFIG "config.hpp" // comment
+            // config
+            #define INDIRECT PLATFORM
+            // linux
+            #include UNDEFINED
+            #define FUNCTION(x) <a.hpp>
+            #include FUNCTION(1)
+            #if UNKNOWN(1)
+            #define MAYBE <a.hpp>
+            #endif
+            #include MAYBE
+        "#});
+    Ok(())
+}
+
 #[test]
 fn line_directives() -> Result<()> {
     let builder = util::builder()
@@ -176,3 +220,663 @@ fn pragma_once_removal() -> Result<()> {
         .stdout("");
     Ok(())
 }
+
+#[test]
+fn header_guard_removal() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #include <a.hpp>
+            #include <b.hpp>
+            #include <c.hpp>
+        "})?
+        .search_dir(
+            "-d",
+            [
+                ("a.hpp", "// a.hpp\n#ifndef A_HPP\n#define A_HPP\na\n#endif\n"),
+                (
+                    "b.hpp",
+                    "/* b.hpp\n */\n#if !defined(B_HPP)\n#define B_HPP\n#ifdef X\n#endif\nb\n#endif // B_HPP\n",
+                ),
+                ("c.hpp", "#ifndef C_HPP\n#define C_HPP\nc\n#endif\nc\n"),
+            ],
+        )?
+        .command()
+        .arg("--strip-header-guards")
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            // a.hpp
+            a
+            /* b.hpp
+             */
+            #ifdef X
+            #endif
+            b
+            #ifndef C_HPP
+            #define C_HPP
+            c
+            #endif
+            c
+        "});
+    Ok(())
+}
+
+#[test]
+fn header_guards_are_kept_by_default() -> Result<()> {
+    util::builder()
+        .source_file("#include <a.hpp>\n")?
+        .search_dir(
+            "-d",
+            [("a.hpp", "#ifndef A_HPP\n#define A_HPP\na\n#endif\n")],
+        )?
+        .command()
+        .assert()
+        .success()
+        .stdout("#ifndef A_HPP\n#define A_HPP\na\n#endif\n");
+    Ok(())
+}
+
+#[test]
+fn duplicate_header_guards() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #include <a.hpp>
+            #include <b.hpp>
+        "})?
+        .search_dir(
+            "-d",
+            [
+                ("a.hpp", "#ifndef GUARD\n#define GUARD\n#endif\n"),
+                ("b.hpp", "#ifndef GUARD\n#define GUARD\n#endif\n"),
+            ],
+        )?
+        .command()
+        .arg("--strip-header-guards")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Header guard GUARD is used by both",
+        ));
+    Ok(())
+}
+
+#[test]
+fn multi_include_globs() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #define X(name) int name;
+            #include <fields.def>
+            #undef X
+            #define X(name) #name,
+            #include <fields.def>
+            #include <a.hpp>
+            #include <a.hpp>
+        "})?
+        .search_dir("-d", [("fields.def", "X(a)\nX(b)\n"), ("a.hpp", "a\n")])?
+        .command()
+        .args(["--multi-include", "**/*.def"])
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            #define X(name) int name;
+            X(a)
+            X(b)
+            #undef X
+            #define X(name) #name,
+            X(a)
+            X(b)
+            a
+        "});
+    Ok(())
+}
+
+#[test]
+fn multi_include_unguarded() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #include <a.inc>
+            #include <b.hpp>
+            #include <c.hpp>
+            #include <d.inc>
+            #include <a.inc>
+            #include <b.hpp>
+            #include <c.hpp>
+            #include <d.inc>
+        "})?
+        .search_dir(
+            "-d",
+            [
+                ("a.inc", "a\n"),
+                ("b.hpp", "#pragma once\nb\n"),
+                ("c.hpp", "#ifndef C\n#define C\nc\n#endif\n"),
+                ("d.inc", "d\n"),
+            ],
+        )?
+        .command()
+        .args([
+            "--multi-include-unguarded",
+            "--strip-header-guards",
+            "--multi-include",
+            "!**/d.inc",
+        ])
+        .assert()
+        .success()
+        .stdout("a\nb\nc\nd\na\n");
+    Ok(())
+}
+
+#[test]
+fn multi_include_guarded_headers() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {"
+            #include <t.def>
+            #include <t.def>
+            #include <u.def>
+            #undef U_DEF
+            #include <u.def>
+        "})?
+        .search_dir(
+            "-d",
+            [
+                (
+                    "t.def",
+                    "#ifndef T_DEF\n#define T_DEF\nstruct T {};\n#endif\n",
+                ),
+                (
+                    "u.def",
+                    "#ifndef U_DEF\n#define U_DEF\nstruct U {};\n#endif\n",
+                ),
+            ],
+        )?
+        .command()
+        .args(["--multi-include", "**/*.def", "--strip-header-guards"])
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            struct T {};
+            struct U {};
+            #undef U_DEF
+            struct U {};
+        "});
+    Ok(())
+}
+
+#[test]
+fn cyclic_multi_includes() -> Result<()> {
+    util::builder()
+        .source_file("#include <a.def>")?
+        .search_dir(
+            "-d",
+            [("a.def", "#include <b.def>"), ("b.def", "#include <a.def>")],
+        )?
+        .command()
+        .args(["--multi-include", "**"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn includes_in_comments_and_literals() -> Result<()> {
+    let source = indoc! {r#"
+        /* Example:
+        #include <a.hpp>
+        */
+        // Not continued
+        #include <a.hpp>
+        // Continued: \
+        #include <b.hpp>
+        const char* test = R"cpp(
+        #pragma once
+        #include <b.hpp>
+        )cpp";
+        const char* s = "/*"; #include <b.hpp>
+        #include <c.hpp>
+    "#};
+    util::builder()
+        .source_file(source)?
+        .search_dir(
+            "-d",
+            [
+                ("a.hpp", "// a\n"),
+                ("b.hpp", "// b\n"),
+                ("c.hpp", "// c\n"),
+            ],
+        )?
+        .command()
+        .assert()
+        .success()
+        .stdout(
+            source
+                .replacen("#include <a.hpp>\n//", "// a\n//", 1)
+                .replace("#include <c.hpp>", "// c"),
+        );
+    Ok(())
+}
+
+#[test]
+fn header_guard_with_directives_in_literals() -> Result<()> {
+    util::builder()
+        .source_file("#include <a.hpp>\n")?
+        .search_dir(
+            "-d",
+            [("a.hpp", "#ifndef A\n#define A\nR\"(\n#endif\n)\"\n#endif\n")],
+        )?
+        .command()
+        .arg("--strip-header-guards")
+        .assert()
+        .success()
+        .stdout("R\"(\n#endif\n)\"\n");
+    Ok(())
+}
+
+#[test]
+fn include_statement_spellings() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {r#"
+            #include <a.hpp> // for a
+            %:include <b.hpp>
+            # \
+              include \
+              <c.hpp>
+            #include /* d */ "d.hpp" /* another comment */
+        "#})?
+        .search_dir(
+            "-d",
+            [
+                ("a.hpp", "#pragma once // a\na\n"),
+                ("b.hpp", "%:pragma once\nb\n"),
+                ("c.hpp", "#pragma \\\n  once\nc\n"),
+                ("d.hpp", "d\n"),
+            ],
+        )?
+        .command()
+        .assert()
+        .success()
+        .stdout("a\nb\nc\nd\n");
+    Ok(())
+}
+
+#[test]
+fn line_markers_in_input() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {r#"
+            #line 10 "parser.y"
+            rule
+            #include <a.hpp>
+            rule
+            # 1 "dir\\lexer.l" 1
+            token
+            #line 20
+            token
+            #if 0
+            #line 1 "dead.y"
+            #endif
+            token
+        "#})?
+        .search_dir("-d", [("a.hpp", "qwfp\n")])?;
+
+    let a_hpp = builder.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .to_path_buf()
+        .canonicalize()?;
+
+    builder
+        .command()
+        .arg("--line-directives")
+        .assert()
+        .success()
+        .stdout(formatdoc! {r#"
+            #line 10 "parser.y"
+            rule
+            #line 1 "{a_hpp}"
+            qwfp
+            #line 12 "parser.y"
+            rule
+            #line 1 "dir\\lexer.l"
+            token
+            #line 20 "dir\\lexer.l"
+            token
+            #if 0
+            #line 1 "dead.y"
+            #endif
+            token
+            "#,
+            a_hpp=a_hpp.display()
+        });
+
+    // Without --line-directives, they are kept as is
+    builder
+        .command()
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "#line 10 \"parser.y\"\nrule\nqwfp\n",
+        ));
+    Ok(())
+}
+
+#[test]
+fn gcc_linemarkers() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {"
+            arst
+            #include \"a.hpp\"
+            arst
+        "})?
+        .search_dir("-d", [("a.hpp", "qwfp\n#include <sys.hpp>\n\nqwfp\n")])?
+        .search_dir("--dir-system", [("sys.hpp", "zxcv\n")])?;
+
+    let src_file = builder.source_files[0].to_path_buf().canonicalize()?;
+    let a_hpp = builder.search_dirs[0].1.child("a.hpp").to_path_buf();
+    let a_hpp = a_hpp.canonicalize()?;
+    let sys_hpp = builder.search_dirs[1].1.child("sys.hpp").to_path_buf();
+    let sys_hpp = sys_hpp.canonicalize()?;
+
+    builder
+        .command()
+        .arg("--line-directives=gcc")
+        .assert()
+        .success()
+        .stdout(formatdoc! {r#"
+            # 1 "{src_file}"
+            arst
+            # 1 "{a_hpp}" 1
+            qwfp
+            # 1 "{sys_hpp}" 1 3
+            zxcv
+            # 3 "{a_hpp}" 2
+
+            qwfp
+            # 3 "{src_file}" 2
+            arst
+            "#,
+            src_file=src_file.display(),
+            a_hpp=a_hpp.display(),
+            sys_hpp=sys_hpp.display()
+        });
+    Ok(())
+}
+
+#[test]
+fn gcc_linemarkers_after_continued_include() -> Result<()> {
+    let builder = util::builder()
+        .source_file("#include \\\n    <a.hpp>\nafter\n")?
+        .search_dir("-d", [("a.hpp", "qwfp\n")])?;
+
+    let src_file = builder.source_files[0].to_path_buf().canonicalize()?;
+    let a_hpp = builder.search_dirs[0].1.child("a.hpp").to_path_buf();
+    let a_hpp = a_hpp.canonicalize()?;
+
+    builder
+        .command()
+        .arg("--line-directives=gcc")
+        .assert()
+        .success()
+        .stdout(formatdoc! {r#"
+            # 1 "{src_file}"
+            # 1 "{a_hpp}" 1
+            qwfp
+            # 3 "{src_file}" 2
+            after
+            "#,
+            src_file=src_file.display(),
+            a_hpp=a_hpp.display()
+        });
+    Ok(())
+}
+
+#[test]
+fn line_directive_paths() -> Result<()> {
+    let builder = util::builder()
+        .source_file("#include <a.hpp>\n#include <b.hpp>\n#include <c.hpp>\n")?
+        .search_dir("-d", [("a.hpp", "qwfp\n")])?
+        .search_dir("-d", [("b.hpp", "arst\n"), ("c.hpp", "zxcv\n")])?;
+
+    // All temporary directories are created in the same parent directory
+    let src_dir = builder.source_files[0].path().parent().unwrap();
+    let a_dir = builder.search_dirs[0].1.path().canonicalize()?;
+    let b_dir = builder.search_dirs[1].1.path().canonicalize()?;
+    let b_relative = Path::new("..")
+        .join(b_dir.file_name().unwrap())
+        .join("b.hpp");
+
+    builder
+        .command()
+        .arg("--line-directives")
+        .arg("--line-directive-base")
+        .arg(src_dir)
+        .arg("--remap-path-prefix")
+        .arg(format!("{}=include", a_dir.display()))
+        .arg("--remap-path-prefix")
+        .arg(format!("{}=generated.hpp", b_dir.join("c.hpp").display()))
+        .assert()
+        .success()
+        .stdout(formatdoc! {r#"
+            #line 1 "include/a.hpp"
+            qwfp
+            #line 1 "{b_relative}"
+            arst
+            #line 1 "generated.hpp"
+            zxcv
+            "#,
+            b_relative=b_relative.display()
+        });
+    Ok(())
+}
+
+#[test]
+fn line_directives_with_continued_lines() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {"
+            #define A \\
+              1
+            #include <a.hpp>
+            arst
+        "})?
+        .search_dir("-d", [("a.hpp", "qwfp\n")])?;
+
+    let src_file = builder.source_files[0].to_path_buf().canonicalize()?;
+    let a_hpp = builder.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .to_path_buf()
+        .canonicalize()?;
+
+    builder
+        .command()
+        .arg("--line-directives")
+        .assert()
+        .success()
+        .stdout(formatdoc! {r#"
+            #line 1 "{src_file}"
+            #define A \
+              1
+            #line 1 "{a_hpp}"
+            qwfp
+            #line 4 "{src_file}"
+            arst
+            "#,
+            src_file=src_file.display(),
+            a_hpp=a_hpp.display()
+        });
+    Ok(())
+}
+
+#[test]
+fn include_with_block_comment_spanning_lines() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {"
+            #include <a.hpp> /* x
+            y */
+            arst
+        "})?
+        .search_dir("-d", [("a.hpp", "qwfp\n")])?;
+
+    let src_file = builder.source_files[0].to_path_buf().canonicalize()?;
+    let a_hpp = builder.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .to_path_buf()
+        .canonicalize()?;
+
+    builder
+        .command()
+        .arg("--line-directives")
+        .assert()
+        .success()
+        .stdout(formatdoc! {r#"
+            #line 1 "{a_hpp}"
+            qwfp
+            #line 3 "{src_file}"
+            arst
+            "#,
+            src_file=src_file.display(),
+            a_hpp=a_hpp.display()
+        });
+    Ok(())
+}
+
+#[test]
+fn objective_c_imports() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {r#"
+            #import "a.inc"
+            #include "a.inc"
+            #include <b.inc>
+            #import <b.inc>
+            #include <b.inc>
+        "#})?
+        .search_dir("-d", [("a.inc", "a\n"), ("b.inc", "b\n")])?
+        .command()
+        .arg("--multi-include-unguarded")
+        .assert()
+        .success()
+        .stdout("a\nb\n");
+    Ok(())
+}
+
+#[test]
+fn header_unit_imports() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {r#"
+            #define OUTER
+            import <a.hpp>;
+            export import "b.hpp" ;
+            import <a.hpp>;
+            #ifdef FROM_A
+            #include <c.hpp>
+            #endif
+            import <missing.hpp>;
+        "#})?
+        .search_dir(
+            "-d",
+            [
+                (
+                    "a.hpp",
+                    "#ifdef OUTER\n#include <c.hpp>\n#endif\n#define FROM_A\n",
+                ),
+                ("b.hpp", "// b\n"),
+                ("c.hpp", "// c\n"),
+            ],
+        )?
+        .command()
+        .assert()
+        .success()
+        // a.hpp is inlined after the #define, so it is compiled with OUTER defined
+        .stderr(
+            predicate::str::contains("uses macros defined before its import")
+                .and(predicate::str::contains(": OUTER\n")),
+        )
+        .stdout(indoc! {"
+            #define OUTER
+            #ifdef OUTER
+            #include <c.hpp>
+            #endif
+            #define FROM_A
+            // b
+            #ifdef FROM_A
+            // c
+            #endif
+            import <missing.hpp>;
+        "});
+    Ok(())
+}
+
+#[test]
+fn comment_stripping() -> Result<()> {
+    let builder = util::builder()
+        .source_file(indoc! {r#"
+            /*! Keep */
+            // Copyright
+            int a = 1; // trailing
+            int b/**/= 2;
+            const char* s = "// /* */";
+            auto r = R"x(/* raw */)x"; /* multi
+               line */ int c;
+            char q = '"'; // quote
+            // continued \
+               comment
+            #define A 1 /* c */ \
+              + 2
+            #include <a.hpp>
+        "#})?
+        .search_dir("-d", [("a.hpp", "/* a */\n\nqwfp\n")])?;
+
+    builder
+        .command()
+        .arg("--strip-comments")
+        .assert()
+        .success()
+        .stdout(indoc! {r#"
+            int a = 1;
+            int b = 2;
+            const char* s = "// /* */";
+            auto r = R"x(/* raw */)x";
+             int c;
+            char q = '"';
+            #define A 1  \
+              + 2
+
+            qwfp
+        "#});
+
+    builder
+        .command()
+        .arg("--strip-comments=keep-licenses")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "/*! Keep */\n// Copyright\nint a = 1;\n",
+        ));
+    Ok(())
+}
+
+#[test]
+fn line_directives_with_stripped_comments() -> Result<()> {
+    let builder = util::builder().source_file(indoc! {"
+        // comment
+        arst
+        /* multiple
+           lines */
+        qwfp
+    "})?;
+    let src_file = builder.source_files[0].to_path_buf().canonicalize()?;
+
+    builder
+        .command()
+        .arg("--strip-comments")
+        .arg("--line-directives")
+        .assert()
+        .success()
+        .stdout(formatdoc! {r#"
+            #line 2 "{src_file}"
+            arst
+            #line 5 "{src_file}"
+            qwfp
+            "#,
+            src_file=src_file.display()
+        });
+    Ok(())
+}
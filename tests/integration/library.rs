@@ -0,0 +1,71 @@
+use crate::util;
+
+use anyhow::Result;
+use cpp_amalgamate::{Amalgamator, ErrorHandling, MacroOption};
+use indoc::indoc;
+
+#[test]
+fn amalgamating_into_buffer() -> Result<()> {
+    let setup = util::builder()
+        .source_file(indoc! {"
+            #include <a.hpp>
+            #ifdef WITH_C
+            #include <c.hpp>
+            #endif
+            #include <missing.hpp>
+        "})?
+        .search_dir(
+            "-d",
+            [
+                ("a.hpp", "#include <b.hpp>\n// a\n"),
+                ("b.hpp", "// b\n"),
+                ("c.hpp", "// c\n"),
+            ],
+        )?;
+    let source_file = setup.source_files[0].path();
+    let search_dir = setup.search_dirs[0].1.path();
+
+    let mut output = Vec::new();
+    let amalgamation = Amalgamator::new()
+        .input(source_file)
+        .search_dir(search_dir)
+        .filter("**/c.hpp".parse()?)
+        .macro_option(MacroOption::parse_define("WITH_C")?)
+        .amalgamate(&mut output)?;
+
+    assert_eq!(
+        String::from_utf8(output)?,
+        indoc! {"
+            // b
+            // a
+            #ifdef WITH_C
+            #include <c.hpp>
+            #endif
+            #include <missing.hpp>
+        "}
+    );
+    assert_eq!(amalgamation.source_files, [source_file.canonicalize()?]);
+    assert_eq!(
+        amalgamation.inlined_files,
+        [
+            search_dir.join("a.hpp").canonicalize()?,
+            search_dir.join("b.hpp").canonicalize()?,
+        ]
+    );
+    let source_map = &amalgamation.source_map;
+    let header_a = search_dir.join("a.hpp").canonicalize()?;
+    assert_eq!(source_map.lookup(2), Some((header_a.as_path(), 2)));
+    assert_eq!(source_map.lookup(7), None);
+    Ok(())
+}
+
+#[test]
+fn library_error_handling() -> Result<()> {
+    let setup = util::builder().source_file("#include <missing.hpp>\n")?;
+    let result = Amalgamator::new()
+        .input(setup.source_files[0].path())
+        .unresolvable_include(ErrorHandling::Error)
+        .amalgamate(Vec::new());
+    assert!(result.is_err());
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use crate::util;
+
+use anyhow::Result;
+use assert_fs::{prelude::*, NamedTempFile};
+use indoc::{formatdoc, indoc};
+
+fn setup() -> Result<util::TestSetupBuilder> {
+    util::builder()
+        .source_file(indoc! {"
+            arst
+            #include <a.hpp>
+            arst
+        "})?
+        .search_dir("-d", [("a.hpp", "qwfp\nqwfp\n")])
+}
+
+#[test]
+fn locate_line_using_source_map() -> Result<()> {
+    let setup = setup()?;
+    let header = setup.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .path()
+        .canonicalize()?;
+    let output_file = NamedTempFile::new("out.cpp")?;
+    let source_map_file = NamedTempFile::new("out.map.json")?;
+    setup
+        .command()
+        .arg("-o")
+        .arg(output_file.path())
+        .arg("--source-map")
+        .arg(source_map_file.path())
+        .assert()
+        .success();
+
+    util::command()
+        .arg("locate")
+        .arg(output_file.path())
+        .arg("3")
+        .arg("--map")
+        .arg(source_map_file.path())
+        .assert()
+        .success()
+        .stdout(format!("{}:2\n", header.display()));
+
+    // Without a source map or line directives, the output cannot be mapped
+    util::command()
+        .arg("locate")
+        .arg(output_file.path())
+        .arg("3")
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn rewrite_diagnostics_using_line_directives() -> Result<()> {
+    let setup = setup()?;
+    let source = setup.source_files[0].path().canonicalize()?;
+    let header = setup.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .path()
+        .canonicalize()?;
+    let output_file = NamedTempFile::new("out.cpp")?;
+    setup
+        .command()
+        .arg("-o")
+        .arg(output_file.path())
+        .arg("--line-directives")
+        .assert()
+        .success();
+
+    let output_path = output_file.path().canonicalize()?;
+    util::command()
+        .arg("locate")
+        .arg(output_file.path())
+        .args(["--name", "prog.cpp"])
+        .write_stdin(formatdoc! {"
+            {output}:5:3: error: expected ';'
+            In file included from prog.cpp:7:
+            other/prog.cpp:5:3: note: unrelated
+            {output}:1: note: not copied from any file
+            ",
+            output = output_path.display()
+        })
+        .assert()
+        .success()
+        .stdout(formatdoc! {"
+            {header}:2:3: error: expected ';'
+            In file included from {source}:3:
+            other/prog.cpp:5:3: note: unrelated
+            {output}:1: note: not copied from any file
+            ",
+            header = header.display(),
+            source = source.display(),
+            output = output_path.display()
+        });
+    Ok(())
+}
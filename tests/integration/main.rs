@@ -2,7 +2,16 @@
 mod util;
 
 // Integration tests
+mod annotations;
+mod compile_commands;
+mod conditionals;
+mod config;
 mod filtering;
+mod graph;
 mod inlining;
+mod library;
+mod locate;
 mod misc;
 mod resolving;
+mod source_map;
+mod split;
@@ -1,7 +1,15 @@
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command as StdCommand, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
 use crate::util;
 
 use anyhow::Result;
-use assert_fs::{prelude::*, NamedTempFile};
+use assert_fs::{prelude::*, NamedTempFile, TempDir};
 
 #[test]
 fn invoking_help() {
@@ -53,3 +61,142 @@ fn multiple_source_files() -> Result<()> {
         .stdout("abc");
     Ok(())
 }
+
+#[test]
+fn dependency_files() -> Result<()> {
+    let out_file = NamedTempFile::new("out.cpp")?;
+    let dep_file = NamedTempFile::new("out.d")?;
+    let setup = util::builder()
+        .source_file("#include <a.hpp>\n#include <b c.hpp>\n#include <missing.hpp>\n")?
+        .search_dir(
+            "-d",
+            [("a.hpp", "// a\n"), ("b c.hpp", "#include <a.hpp>\n")],
+        )?;
+    let source = setup.source_files[0].path().canonicalize()?;
+    let dir = setup.search_dirs[0].1.path().canonicalize()?;
+    let header_a = dir.join("a.hpp").display().to_string();
+    let header_b = dir
+        .join("b c.hpp")
+        .display()
+        .to_string()
+        .replace(' ', "\\ ");
+
+    setup
+        .command()
+        .arg("-o")
+        .arg(out_file.path())
+        .arg("--dep-file")
+        .arg(dep_file.path())
+        .assert()
+        .success();
+    dep_file.assert(format!(
+        "{}: \\\n  {} \\\n  {} \\\n  {}\n",
+        out_file.path().display(),
+        source.display(),
+        header_a,
+        header_b
+    ));
+
+    setup
+        .command()
+        .args(["--dep-target", "$(OUT)", "--dep-phony", "--dep-file"])
+        .arg(dep_file.path())
+        .assert()
+        .success();
+    dep_file.assert(format!(
+        "$$(OUT): \\\n  {} \\\n  {} \\\n  {}\n\n{}:\n\n{}:\n",
+        source.display(),
+        header_a,
+        header_b,
+        header_a,
+        header_b
+    ));
+
+    // Without an output file, there is no default target
+    setup
+        .command()
+        .arg("--dep-file")
+        .arg(dep_file.path())
+        .assert()
+        .failure()
+        .stdout("");
+    Ok(())
+}
+
+/// Kills the child process when dropped, even if the test fails.
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Waits until the watching process reports that it is watching for changes again.
+///
+/// The process only does so after it wrote the output and recorded the state of all watched files,
+/// so any change made afterwards is detected.
+fn wait_for_watching(stderr_lines: &Receiver<String>) {
+    loop {
+        let line = stderr_lines
+            .recv_timeout(Duration::from_secs(60))
+            .expect("timed out waiting for the files to be watched");
+        if line.contains("Watching") {
+            return;
+        }
+    }
+}
+
+#[test]
+fn watching_for_changes() -> Result<()> {
+    let out_file = NamedTempFile::new("out.cpp")?;
+    let setup = util::builder()
+        .source_file("#include <a.hpp>\n")?
+        .search_dir("-d", [("a.hpp", "// a\n")])?;
+    let dir = &setup.search_dirs[0].1;
+    // Search directories which are not given on the command line are watched as well
+    let env_dir = TempDir::new()?;
+
+    let mut command = StdCommand::new(util::binary());
+    command
+        .arg(setup.source_files[0].path())
+        .arg("-d")
+        .arg(dir.path())
+        .arg("-o")
+        .arg(out_file.path())
+        .args(["--watch", "-v", "--use-env-paths"])
+        .env("CPATH", env_dir.path())
+        .env_remove("CPLUS_INCLUDE_PATH")
+        .stderr(Stdio::piped());
+    let mut child = KillOnDrop(command.spawn()?);
+    let stderr = child.0.stderr.take().expect("stderr should be piped");
+    let (sender, stderr_lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            if line.map(|line| sender.send(line)).is_err() {
+                break;
+            }
+        }
+    });
+    wait_for_watching(&stderr_lines);
+    out_file.assert("// a\n");
+
+    // Changes to inlined headers
+    dir.child("a.hpp").write_str("#include <b.hpp>\n// a\n")?;
+    wait_for_watching(&stderr_lines);
+    out_file.assert("#include <b.hpp>\n// a\n");
+
+    // Newly created headers in a search directory
+    env_dir.child("b.hpp").write_str("// b\n")?;
+    wait_for_watching(&stderr_lines);
+    out_file.assert("// b\n// a\n");
+
+    // Errors do not stop watching
+    env_dir.child("b.hpp").write_str("#include <a.hpp>\n")?;
+    wait_for_watching(&stderr_lines);
+    env_dir.child("b.hpp").write_str("// b2\n")?;
+    wait_for_watching(&stderr_lines);
+    out_file.assert("// b2\n// a\n");
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use crate::util;
 
 use anyhow::Result;
-use assert_fs::prelude::*;
+use assert_fs::{prelude::*, TempDir};
 use indoc::indoc;
 use predicates::prelude::*;
 
@@ -160,3 +160,212 @@ fn unresolvable_include_error_options() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn search_dirs_from_environment() -> Result<()> {
+    let dir = TempDir::new()?;
+    dir.child("main.cpp")
+        .write_str("#include <a.hpp>\n#include <b.hpp>\n#include <c.hpp>\n#include <d.hpp>\n")?;
+    dir.child("cpath/a.hpp").write_str("// a cpath\n")?;
+    dir.child("cxx/a.hpp").write_str("// a cxx\n")?;
+    dir.child("cxx/b.hpp").write_str("// b cxx\n")?;
+    dir.child("c/b.hpp").write_str("// b c\n")?;
+    dir.child("c.hpp").write_str("// c\n")?;
+    dir.child("cli/d.hpp").write_str("// d cli\n")?;
+    dir.child("cpath/d.hpp").write_str("// d cpath\n")?;
+    let command = || {
+        let mut command = util::command();
+        command
+            .current_dir(dir.path())
+            .env("CPATH", "cpath:missing:")
+            .env("C_INCLUDE_PATH", "c")
+            .env("CPLUS_INCLUDE_PATH", "cxx")
+            .args(["-d", "cli", "main.cpp"]);
+        command
+    };
+
+    command()
+        .assert()
+        .success()
+        .stdout("#include <a.hpp>\n#include <b.hpp>\n#include <c.hpp>\n// d cli\n");
+    command()
+        .arg("--use-env-paths")
+        .assert()
+        .success()
+        .stdout("// a cpath\n// b cxx\n// c\n// d cli\n");
+    command()
+        .args(["--use-env-paths", "--language", "c"])
+        .assert()
+        .success()
+        .stdout("// a cpath\n// b c\n// c\n// d cli\n");
+
+    // Unset or empty variables don't add the current directory
+    for empty in [None, Some("")] {
+        let mut command = util::command();
+        command.current_dir(dir.path());
+        for var in ["CPATH", "C_INCLUDE_PATH", "CPLUS_INCLUDE_PATH"] {
+            match empty {
+                Some(value) => command.env(var, value),
+                None => command.env_remove(var),
+            };
+        }
+        command
+            .args(["--use-env-paths", "main.cpp"])
+            .assert()
+            .success()
+            .stdout("#include <a.hpp>\n#include <b.hpp>\n#include <c.hpp>\n#include <d.hpp>\n");
+    }
+    Ok(())
+}
+
+#[test]
+fn precedence_of_environment_and_compile_commands_dirs() -> Result<()> {
+    let dir = TempDir::new()?;
+    dir.child("compile_commands.json").write_str(indoc! {r#"
+        [{
+            "directory": ".",
+            "file": "main.cpp",
+            "command": "c++ -Iinclude -isystem system -idirafter after -c main.cpp"
+        }]
+    "#})?;
+    dir.child("main.cpp")
+        .write_str("#include <a.hpp>\n#include <b.hpp>\n#include <c.hpp>\n#include <d.hpp>\n")?;
+    dir.child("include/a.hpp").write_str("// a include\n")?;
+    dir.child("cpath/a.hpp").write_str("// a cpath\n")?;
+    dir.child("cpath/b.hpp").write_str("// b cpath\n")?;
+    dir.child("system/b.hpp").write_str("// b system\n")?;
+    dir.child("system/c.hpp").write_str("// c system\n")?;
+    dir.child("cxx/c.hpp").write_str("// c cxx\n")?;
+    dir.child("cxx/d.hpp").write_str("// d cxx\n")?;
+    dir.child("after/d.hpp").write_str("// d after\n")?;
+
+    util::command()
+        .current_dir(dir.path())
+        .env("CPATH", "cpath")
+        .env("CPLUS_INCLUDE_PATH", "cxx")
+        .args([
+            "--use-env-paths",
+            "--compile-commands",
+            "compile_commands.json",
+            "main.cpp",
+        ])
+        .assert()
+        .success()
+        .stdout("// a include\n// b cpath\n// c system\n// d cxx\n");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn system_dirs_from_compiler() -> Result<()> {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let dir = TempDir::new()?;
+    let builtin_dir = dir.child("builtin");
+    builtin_dir.child("vector").write_str("// vector\n")?;
+    let compiler = dir.child("bin/fake-g++");
+    compiler.write_str(&format!(
+        indoc! {r#"
+            #!/bin/sh
+            echo "$@" >> "$(dirname "$0")/calls"
+            cat >&2 <<END
+            Using built-in specs.
+            #include "..." search starts here:
+            #include <...> search starts here:
+             {}
+             /nonexistent (framework directory)
+            End of search list.
+            END
+        "#},
+        builtin_dir.path().display()
+    ))?;
+    fs::set_permissions(compiler.path(), fs::Permissions::from_mode(0o755))?;
+    dir.child("cpp-amalgamate.toml").write_str(indoc! {r#"
+        [defaults]
+        system-dirs-from = "bin/fake-g++"
+
+        [targets.a]
+        inputs = ["a.cpp"]
+        output = "a.out.cpp"
+
+        [targets.b]
+        inputs = ["b.cpp"]
+        output = "b.out.cpp"
+    "#})?;
+    dir.child("a.cpp").write_str("#include <vector>\n")?;
+    dir.child("b.cpp").write_str("#include <vector>\n// b\n")?;
+
+    let cache_dir = dir.child("cache");
+    let command = || {
+        let mut command = util::command();
+        command
+            .current_dir(dir.path())
+            .env("XDG_CACHE_HOME", cache_dir.path());
+        command
+    };
+    command().arg("build").assert().success();
+    dir.child("a.out.cpp").assert("// vector\n");
+    dir.child("b.out.cpp").assert("// vector\n// b\n");
+    // The compiler only runs once, even though both targets use it
+    dir.child("bin/calls").assert("-E -v -x c++ -\n");
+
+    command()
+        .args(["--language", "c", "a.cpp"])
+        .assert()
+        .success()
+        .stdout("// vector\n");
+    dir.child("bin/calls")
+        .assert("-E -v -x c++ -\n-E -v -x c -\n");
+
+    // The search directories are cached across runs, until the compiler changes
+    command().arg("build").assert().success();
+    dir.child("bin/calls")
+        .assert("-E -v -x c++ -\n-E -v -x c -\n");
+    let script = fs::read_to_string(compiler.path())?;
+    compiler.write_str(&format!("{}\n", script))?;
+    command().arg("build").assert().success();
+    dir.child("bin/calls")
+        .assert("-E -v -x c++ -\n-E -v -x c -\n-E -v -x c++ -\n");
+    // Only the entry for the current version of the compiler is kept
+    let cache: Vec<serde_json::Value> = serde_json::from_str(&fs::read_to_string(
+        cache_dir.child("cpp-amalgamate/system-dirs.json").path(),
+    )?)?;
+    assert_eq!(cache.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn include_next() -> Result<()> {
+    util::builder()
+        .source_file(indoc! {r#"
+            #include <stdio.h>
+            #include "config.h"
+            #include_next <vector>
+        "#})?
+        .search_dir(
+            "-d",
+            [
+                (
+                    "stdio.h",
+                    "// wrapper\n#if __has_include_next(<stdio.h>)\n#include_next <stdio.h>\n#endif\n",
+                ),
+                ("config.h", "// config wrapper\n#include_next \"config.h\"\n"),
+                ("vector", "// vector\n"),
+            ],
+        )?
+        .search_dir("--dir-quote", [("config.h", "// config\n")])?
+        .search_dir("-d", [("stdio.h", "// stdio\n"), ("config.h", "// unused\n")])?
+        .command()
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            // wrapper
+            #if __has_include_next(<stdio.h>)
+            // stdio
+            #endif
+            // config wrapper
+            // config
+            // vector
+        "});
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::fs;
+
+use crate::util;
+
+use anyhow::Result;
+use assert_fs::{prelude::*, NamedTempFile};
+use indoc::indoc;
+use serde_json::{json, Value};
+
+#[test]
+fn json_source_map() -> Result<()> {
+    let setup = util::builder()
+        .source_file(indoc! {"
+            arst
+            #include <a.hpp>
+            arst
+            arst
+        "})?
+        .search_dir("-d", [("a.hpp", "#pragma once\nqwfp\nqwfp\n")])?;
+    let source = setup.source_files[0].path().canonicalize()?;
+    let header = setup.search_dirs[0]
+        .1
+        .child("a.hpp")
+        .path()
+        .canonicalize()?;
+    let source_map_file = NamedTempFile::new("source-map.json")?;
+
+    setup
+        .command()
+        .arg("--source-map")
+        .arg(source_map_file.path())
+        .assert()
+        .success()
+        .stdout("arst\nqwfp\nqwfp\narst\narst\n");
+    let source_map: Value = serde_json::from_str(&fs::read_to_string(source_map_file.path())?)?;
+    assert_eq!(
+        source_map,
+        json!({
+            "version": 1,
+            "files": [source, header],
+            "ranges": [[1, 0, 1, 1], [2, 1, 2, 2], [4, 0, 3, 2]],
+        })
+    );
+
+    // Line directives are not mapped
+    setup
+        .command()
+        .args(["--line-directives", "--source-map"])
+        .arg(source_map_file.path())
+        .assert()
+        .success();
+    let source_map: Value = serde_json::from_str(&fs::read_to_string(source_map_file.path())?)?;
+    assert_eq!(
+        source_map["ranges"],
+        json!([[2, 0, 1, 1], [4, 1, 2, 2], [7, 0, 3, 2]])
+    );
+    Ok(())
+}
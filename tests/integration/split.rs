@@ -0,0 +1,169 @@
+use std::fs;
+
+use crate::util;
+
+use anyhow::Result;
+use assert_fs::{prelude::*, NamedTempFile, TempDir};
+use indoc::indoc;
+use predicates::prelude::*;
+
+const SOURCE: &str = indoc! {"
+    #include <a.hpp>
+    int main() {
+        return f();
+    }
+    "};
+const HEADER_A: &str = indoc! {"
+    #pragma once
+    #include <b.hpp>
+    int f() { return g(); }
+    "};
+const HEADER_B: &str = "int g() { return 1; }\n";
+
+fn setup() -> Result<util::TestSetupBuilder> {
+    util::builder()
+        .source_file(SOURCE)?
+        .search_dir("-d", [("a.hpp", HEADER_A), ("b.hpp", HEADER_B)])
+}
+
+#[test]
+fn split_using_line_directives() -> Result<()> {
+    let setup = setup()?;
+    let output_file = NamedTempFile::new("out.cpp")?;
+    setup
+        .command()
+        .arg("-o")
+        .arg(output_file.path())
+        .arg("--line-directives")
+        .assert()
+        .success();
+    let amalgamated = fs::read_to_string(output_file.path())?
+        .replace("return 1;", "return 2;")
+        .replace("    return f();\n", "    int x = f();\n    return x;\n");
+    output_file.write_str(&amalgamated)?;
+
+    util::command()
+        .arg("split")
+        .arg(output_file.path())
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("-int g() { return 1; }\n+int g() { return 2; }\n")
+                .and(predicate::str::contains(
+                    "+    int x = f();\n+    return x;\n",
+                ))
+                .and(predicate::str::contains("/a.hpp\n").not()),
+        );
+
+    let out_dir = TempDir::new()?;
+    util::command()
+        .arg("split")
+        .arg(output_file.path())
+        .arg("--out-dir")
+        .arg(out_dir.path())
+        .assert()
+        .success();
+
+    // The files are placed relative to the temporary directory containing all of them
+    let source_dir = setup.source_files[0].path().parent().unwrap();
+    let search_dir = setup.search_dirs[0].1.path();
+    let split_dir = |dir: &std::path::Path| -> Result<_> {
+        Ok(out_dir.child(dir.canonicalize()?.file_name().unwrap()))
+    };
+    split_dir(source_dir)?.child("src.cpp").assert(indoc! {"
+        #include <a.hpp>
+        int main() {
+            int x = f();
+            return x;
+        }
+    "});
+    split_dir(search_dir)?.child("a.hpp").assert(HEADER_A);
+    split_dir(search_dir)?
+        .child("b.hpp")
+        .assert("int g() { return 2; }\n");
+    Ok(())
+}
+
+#[test]
+fn split_using_source_map() -> Result<()> {
+    let setup = setup()?;
+    let output_file = NamedTempFile::new("out.cpp")?;
+    let source_map_file = NamedTempFile::new("out.map.json")?;
+    setup
+        .command()
+        .arg("-o")
+        .arg(output_file.path())
+        .arg("--source-map")
+        .arg(source_map_file.path())
+        .assert()
+        .success();
+    let amalgamated = fs::read_to_string(output_file.path())?.replace("f()", "h()");
+    output_file.write_str(&amalgamated)?;
+
+    util::command()
+        .arg("split")
+        .arg(output_file.path())
+        .arg("--map")
+        .arg(source_map_file.path())
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("-int f() { return g(); }\n+int h() { return g(); }\n")
+                .and(predicate::str::contains(
+                    "-    return f();\n+    return h();\n",
+                ))
+                .and(predicate::str::contains("/b.hpp\n").not()),
+        );
+    Ok(())
+}
+
+#[test]
+fn split_with_stripped_comments() -> Result<()> {
+    const HEADER: &str = indoc! {"
+        // Copyright notice
+        #pragma once
+
+        /* The answer,
+         * computed carefully */
+        int f() { return 42; } // inline comment
+        int g() { return 1; }
+        "};
+    for mode in ["all", "keep-licenses"] {
+        let setup = util::builder()
+            .source_file("#include <a.hpp>\nint main() {}\n")?
+            .search_dir("-d", [("a.hpp", HEADER)])?;
+        let output_file = NamedTempFile::new("out.cpp")?;
+        setup
+            .command()
+            .arg("-o")
+            .arg(output_file.path())
+            .arg("--line-directives")
+            .arg(format!("--strip-comments={}", mode))
+            .assert()
+            .success();
+
+        util::command()
+            .arg("split")
+            .arg(output_file.path())
+            .assert()
+            .success()
+            .stdout("");
+
+        let amalgamated = fs::read_to_string(output_file.path())?.replace("return 1;", "return 2;");
+        output_file.write_str(&amalgamated)?;
+        let out_dir = TempDir::new()?;
+        util::command()
+            .arg("split")
+            .arg(output_file.path())
+            .arg("--out-dir")
+            .arg(out_dir.path())
+            .assert()
+            .success();
+        let search_dir = setup.search_dirs[0].1.path().canonicalize()?;
+        out_dir
+            .child(search_dir.file_name().unwrap())
+            .child("a.hpp")
+            .assert(HEADER.replace("return 1;", "return 2;"));
+    }
+    Ok(())
+}
@@ -1,6 +1,6 @@
 // Usages by other integration tests don't seem to be picked up consistently?
 #![allow(dead_code)]
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use assert_cmd::Command;
@@ -10,6 +10,10 @@ use once_cell::sync::Lazy;
 static BINARY: Lazy<PathBuf> =
     Lazy::new(|| assert_cmd::cargo::cargo_bin(assert_cmd::crate_name!()));
 
+pub fn binary() -> &'static Path {
+    &BINARY
+}
+
 pub fn command() -> Command {
     Command::new(&*BINARY)
 }